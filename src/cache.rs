@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bs1770::{Power, Windows100ms};
+use claxon::FlacReader;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::error::{Error, Result};
+use crate::loudness::TrackScan;
+
+/// Version of the cache file format and of the analysis that produced its
+/// entries. Bump this whenever either changes, so stale entries are dropped.
+const CACHE_VERSION: u32 = 1;
+
+/// Returns the default location of the loudness cache file, based on the
+/// XDG cache directory (or `~/.cache` if it is not set).
+pub(crate) fn default_cache_path() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))?;
+
+    Some(cache_dir.join("marktag").join("loudness.json"))
+}
+
+/// Reads the MD5 signature of the decoded audio from a FLAC file's
+/// STREAMINFO block, as a hex string. Since the signature only covers the
+/// audio, it does not change when the file is retagged. Returns `None` if the
+/// encoder did not record a signature.
+pub(crate) fn audio_signature(track_path: &Path) -> Option<String> {
+    let reader = FlacReader::open(track_path).ok()?;
+    let md5sum = reader.streaminfo().md5sum;
+
+    if md5sum == [0u8; 16] {
+        return None;
+    }
+
+    Some(md5sum.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedScan {
+    windows: Vec<f32>,
+    sample_peak: f32,
+    true_peak: f32,
+}
+
+impl From<&TrackScan> for CachedScan {
+    fn from(scan: &TrackScan) -> Self {
+        Self {
+            windows: scan.windows.inner.iter().map(|p| p.0).collect(),
+            sample_peak: scan.sample_peak,
+            true_peak: scan.true_peak,
+        }
+    }
+}
+
+impl From<&CachedScan> for TrackScan {
+    fn from(cached: &CachedScan) -> Self {
+        Self {
+            windows: Windows100ms {
+                inner: cached.windows.iter().map(|p| Power(*p)).collect(),
+            },
+            sample_peak: cached.sample_peak,
+            true_peak: cached.true_peak,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CacheFile {
+    version: u32,
+    scans: HashMap<String, CachedScan>,
+}
+
+/// A persistent cache of track scans, keyed on the audio signature of the
+/// scanned track.
+pub(crate) struct LoudnessCache {
+    path: PathBuf,
+    scans: HashMap<String, CachedScan>,
+}
+
+impl LoudnessCache {
+    /// Opens the cache file at the given path. A missing, unreadable or
+    /// outdated cache file results in an empty cache.
+    pub fn open(path: PathBuf) -> Self {
+        let scans = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<CacheFile>(&contents).ok())
+            .filter(|cache_file| cache_file.version == CACHE_VERSION)
+            .map(|cache_file| cache_file.scans)
+            .unwrap_or_default();
+
+        Self { path, scans }
+    }
+
+    pub fn get(&self, signature: &str) -> Option<TrackScan> {
+        self.scans.get(signature).map(TrackScan::from)
+    }
+
+    pub fn insert(&mut self, signature: String, scan: &TrackScan) {
+        self.scans.insert(signature, CachedScan::from(scan));
+    }
+
+    pub fn save(self) -> Result<()> {
+        eprintln!("Saving loudness cache: {}", self.path.display());
+
+        // A cache path without a directory is relative to the current one.
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::create_dir_all(dir).map_err(Error::io(dir))?;
+
+        let cache_file = CacheFile {
+            version: CACHE_VERSION,
+            scans: self.scans,
+        };
+        let serialized = serde_json::to_string(&cache_file).expect("scans are always serializable");
+
+        // Write to a temporary file next to the cache and move it into place,
+        // so that an interrupted or overlapping run never truncates the cache.
+        let path = self.path.as_path();
+        let mut temp_file = NamedTempFile::new_in(dir).map_err(Error::io(dir))?;
+        temp_file
+            .write_all(serialized.as_bytes())
+            .map_err(Error::io(path))?;
+        temp_file
+            .persist(path)
+            .map(|_| ())
+            .map_err(|err| Error::io(path)(err.error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_loudness_cache__round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_path = temp_dir.path().join("nested").join("loudness.json");
+
+        let scan = TrackScan {
+            windows: Windows100ms {
+                inner: vec![Power(0.1), Power(0.012_345_678), Power(1e-9)],
+            },
+            sample_peak: 0.75,
+            true_peak: 0.8,
+        };
+
+        let mut cache = LoudnessCache::open(cache_path.clone());
+        assert!(cache.get("abc").is_none());
+        cache.insert(String::from("abc"), &scan);
+        cache.save().unwrap();
+
+        let cache = LoudnessCache::open(cache_path);
+        let cached = cache.get("abc").unwrap();
+
+        // Cached windows must be bit-identical to the originals.
+        let powers = |scan: &TrackScan| scan.windows.inner.iter().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(powers(&cached), powers(&scan));
+        assert_eq!(cached.sample_peak, scan.sample_peak);
+        assert_eq!(cached.true_peak, scan.true_peak);
+    }
+
+    #[test]
+    fn test_loudness_cache__outdated_version() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_path = temp_dir.path().join("loudness.json");
+
+        std::fs::write(
+            &cache_path,
+            r#"{"version": 0, "scans": {"abc": {"windows": [], "sample_peak": 0.0, "true_peak": 0.0}}}"#,
+        )
+        .unwrap();
+
+        let cache = LoudnessCache::open(cache_path);
+        assert!(cache.get("abc").is_none());
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::display::ArtistJoin;
+use crate::error::{Error, Result};
+use crate::template::Template;
+
+/// Defaults for options that are usually the same on every run, read from a
+/// JSON file. Options given on the command line take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Template for the names of output files, without the extension.
+    pub file_name_template: Option<Template>,
+    /// Template for the album directory inside of the output directory.
+    pub output_dir_template: Option<Template>,
+    /// How multiple artists are joined in names.
+    pub artist_join: Option<ArtistJoin>,
+}
+
+/// Returns the default location of the config file, based on the XDG config
+/// directory (or `~/.config` if it is not set).
+pub(crate) fn default_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+
+    Some(config_dir.join("marktag").join("config.json"))
+}
+
+impl Config {
+    /// Loads the config file at the given path. Without a path, the config
+    /// file at the default location is loaded if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path().filter(|p| p.exists()) {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+
+        eprintln!("Loading config file: {}", path.display());
+
+        let contents = std::fs::read_to_string(&path).map_err(Error::io(&path))?;
+        serde_json::from_str(&contents).map_err(Error::json(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_config__load() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("config.json");
+
+        std::fs::write(
+            &path,
+            r#"{"file_name_template": "{tracknumber:02} {title}"}"#,
+        )
+        .unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(
+            config.file_name_template,
+            Some("{tracknumber:02} {title}".parse().unwrap())
+        );
+
+        std::fs::write(&path, r#"{"artist_join": "feat"}"#).unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.artist_join, Some(ArtistJoin::Feat));
+
+        std::fs::write(&path, r#"{"file_name_template": "{title"}"#).unwrap();
+        assert!(matches!(Config::load(Some(&path)), Err(Error::Json { .. })));
+
+        std::fs::write(&path, r#"{"file_name_templat": "{title}"}"#).unwrap();
+        assert!(Config::load(Some(&path)).is_err());
+    }
+}
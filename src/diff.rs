@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+use metaflac::Tag;
+use serde::Serialize;
+
+use crate::helpers;
+use crate::metadata::MetaBlock;
+use crate::plan::TrackPlan;
+use crate::writer::GainOptions;
+
+/// Tags in their vorbis comment form: lowercased keys, each with its values.
+type Comments = BTreeMap<String, Vec<String>>;
+
+/// How to print the tag diff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DiffFormat {
+    /// Colored, unified-diff-like text.
+    Text,
+    /// JSON, one entry per track.
+    Json,
+}
+
+/// A single difference between the existing and incoming tags of a track.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub(crate) enum TagChange {
+    Added {
+        key: String,
+        values: Vec<String>,
+    },
+    Removed {
+        key: String,
+        values: Vec<String>,
+    },
+    Changed {
+        key: String,
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TrackDiff {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disc: Option<usize>,
+    track: usize,
+    file: String,
+    output_file: String,
+    changes: Vec<TagChange>,
+}
+
+/// Reads the existing vorbis comments of a track. Keys are lowercased, since
+/// vorbis comment field names are case-insensitive.
+fn existing_comments(tag: &Tag) -> Comments {
+    let mut comments = Comments::new();
+
+    if let Some(vorbis_comments) = tag.vorbis_comments() {
+        for (key, values) in vorbis_comments.comments.iter() {
+            comments
+                .entry(key.to_ascii_lowercase())
+                .or_default()
+                .extend(values.iter().cloned());
+        }
+    }
+
+    comments
+}
+
+fn incoming_comments(tags: &MetaBlock) -> Comments {
+    tags.iter()
+        .map(|(key, val)| (key.to_ascii_lowercase(), val.clone().into_vec()))
+        .collect()
+}
+
+/// Compares two sets of comments, in key order. The given gain tags are left
+/// out, since they are recalculated after the new tags are written.
+fn diff_comments(existing: &Comments, incoming: &Comments, gain_tags: &[&str]) -> Vec<TagChange> {
+    let mut keys = existing.keys().chain(incoming.keys()).collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter(|key| !gain_tags.contains(&key.as_str()))
+        .filter_map(|key| match (existing.get(key), incoming.get(key)) {
+            (None, Some(new)) => Some(TagChange::Added {
+                key: key.clone(),
+                values: new.clone(),
+            }),
+            (Some(old), None) => Some(TagChange::Removed {
+                key: key.clone(),
+                values: old.clone(),
+            }),
+            (Some(old), Some(new)) if old != new => Some(TagChange::Changed {
+                key: key.clone(),
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compares the existing tags of each planned track with the tags that will
+/// be written to it, including the gain tags of the given options.
+pub(crate) fn diff_plans(plans: &[TrackPlan], gain_options: &GainOptions) -> Vec<TrackDiff> {
+    let gain_tags = gain_options.tag_keys();
+
+    plans
+        .iter()
+        .map(|plan| TrackDiff {
+            disc: plan.track.disc,
+            track: plan.track.index,
+            file: plan
+                .track
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            output_file: plan.output_file_name.clone(),
+            changes: diff_comments(
+                &existing_comments(&plan.track.tag),
+                &incoming_comments(&plan.tags),
+                &gain_tags,
+            ),
+        })
+        .collect()
+}
+
+/// ANSI styles for the text diff, which are left empty when stdout is not a
+/// terminal or when `NO_COLOR` is set.
+struct Styles {
+    header: &'static str,
+    hunk: &'static str,
+    removed: &'static str,
+    added: &'static str,
+    reset: &'static str,
+}
+
+impl Styles {
+    fn detect() -> Self {
+        if std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+            Self {
+                header: "\x1b[1m",
+                hunk: "\x1b[36m",
+                removed: "\x1b[31m",
+                added: "\x1b[32m",
+                reset: "\x1b[0m",
+            }
+        } else {
+            Self {
+                header: "",
+                hunk: "",
+                removed: "",
+                added: "",
+                reset: "",
+            }
+        }
+    }
+}
+
+fn print_lines(style: &str, reset: &str, sign: char, key: &str, values: &[String]) {
+    for value in values {
+        println!("{}{}{}={}{}", style, sign, key, value, reset);
+    }
+}
+
+/// Prints the diffs as `-key=value` and `+key=value` lines, grouped by track.
+pub(crate) fn print_diff(diffs: &[TrackDiff]) {
+    let styles = Styles::detect();
+
+    for diff in diffs {
+        println!("{}--- {}{}", styles.header, diff.file, styles.reset);
+        println!("{}+++ {}{}", styles.header, diff.output_file, styles.reset);
+        println!(
+            "{}@@ track {}: {} change(s) @@{}",
+            styles.hunk,
+            helpers::format_position(diff.disc, diff.track),
+            diff.changes.len(),
+            styles.reset,
+        );
+
+        for change in diff.changes.iter() {
+            match change {
+                TagChange::Added { key, values } => {
+                    print_lines(styles.added, styles.reset, '+', key, values)
+                }
+                TagChange::Removed { key, values } => {
+                    print_lines(styles.removed, styles.reset, '-', key, values)
+                }
+                TagChange::Changed { key, old, new } => {
+                    print_lines(styles.removed, styles.reset, '-', key, old);
+                    print_lines(styles.added, styles.reset, '+', key, new);
+                }
+            }
+        }
+
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use big_s::S;
+    use maplit::btreemap;
+
+    #[test]
+    fn test_diff_comments() {
+        let existing = btreemap! {
+            S("album") => vec![S("Album")],
+            S("artist") => vec![S("Old")],
+            S("comment") => vec![S("Ripped")],
+            S("genre") => vec![S("Rock"), S("Pop")],
+            S("replaygain_track_gain") => vec![S("-7.00 dB")],
+        };
+        let incoming = btreemap! {
+            S("album") => vec![S("Album")],
+            S("artist") => vec![S("New")],
+            S("genre") => vec![S("Rock")],
+            S("title") => vec![S("Title")],
+        };
+
+        assert_eq!(
+            diff_comments(&existing, &incoming, &["replaygain_track_gain"]),
+            vec![
+                TagChange::Changed {
+                    key: S("artist"),
+                    old: vec![S("Old")],
+                    new: vec![S("New")],
+                },
+                TagChange::Removed {
+                    key: S("comment"),
+                    values: vec![S("Ripped")],
+                },
+                TagChange::Changed {
+                    key: S("genre"),
+                    old: vec![S("Rock"), S("Pop")],
+                    new: vec![S("Rock")],
+                },
+                TagChange::Added {
+                    key: S("title"),
+                    values: vec![S("Title")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_comments__unchanged() {
+        let comments = btreemap! {
+            S("artist") => vec![S("Artist")],
+        };
+
+        assert!(diff_comments(&comments, &comments, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_diff_comments__stale_gain_tags() {
+        let existing = btreemap! {
+            S("r128_track_gain") => vec![S("-1792")],
+            S("replaygain_track_gain") => vec![S("-7.00 dB")],
+        };
+        let incoming = Comments::new();
+
+        assert_eq!(
+            diff_comments(
+                &existing,
+                &incoming,
+                &["r128_album_gain", "r128_track_gain"]
+            ),
+            vec![TagChange::Removed {
+                key: S("replaygain_track_gain"),
+                values: vec![S("-7.00 dB")],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tag_change__serialize() {
+        let change = TagChange::Changed {
+            key: S("artist"),
+            old: vec![S("Old")],
+            new: vec![S("New")],
+        };
+
+        assert_eq!(
+            serde_json::to_value(&change).unwrap(),
+            serde_json::json!({
+                "change": "changed",
+                "key": "artist",
+                "old": ["Old"],
+                "new": ["New"],
+            })
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::metadata::{MetaBlock, MetaVal};
+
+/// Keys whose values are joined with an [`ArtistJoin`] style in names.
+const ARTIST_KEYS: &[&str] = &["artist", "albumartist"];
+
+/// How multiple artists are joined in file and directory names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ArtistJoin {
+    /// `A, B, C`
+    #[default]
+    Comma,
+    /// `A & B & C`
+    Ampersand,
+    /// `A, B & C`
+    List,
+    /// `A feat. B`, or `A feat. B, C & D`
+    Feat,
+}
+
+impl ArtistJoin {
+    pub fn join(self, artists: &[String]) -> String {
+        match (self, artists) {
+            (_, []) => String::new(),
+            (_, [artist]) => artist.clone(),
+            (Self::Comma, _) => artists.join(", "),
+            (Self::Ampersand, _) => artists.join(" & "),
+            (Self::List, [init @ .., last]) => format!("{} & {}", init.join(", "), last),
+            (Self::Feat, [first, rest @ ..]) => {
+                format!("{} feat. {}", first, Self::List.join(rest))
+            }
+        }
+    }
+}
+
+/// The tags of a track or album as they are shown in file and directory
+/// names. Multiple artists are joined, unless an explicit `display<key>` or
+/// `<key>sort` key is present, which is then used instead (e.g.
+/// `displayartist` or `artistsort` for `artist`). Keys must be lowercase.
+pub(crate) fn display_tags(tags: &MetaBlock, artist_join: ArtistJoin) -> MetaBlock {
+    let mut display_tags = tags.clone();
+
+    for key in ARTIST_KEYS {
+        let explicit = [format!("display{}", key), format!("{}sort", key)]
+            .iter()
+            .find_map(|explicit_key| tags.get(explicit_key))
+            .map(|val| val.to_string());
+
+        let display = match (explicit, tags.get(*key)) {
+            (Some(explicit), _) => explicit,
+            (None, Some(MetaVal::Many(artists))) => artist_join.join(artists),
+            (None, _) => continue,
+        };
+
+        display_tags.insert(String::from(*key), MetaVal::One(display));
+    }
+
+    display_tags
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use big_s::S;
+    use maplit::btreemap;
+
+    #[test]
+    fn test_artist_join__join() {
+        let two = vec![S("Dani J"), S("Caluu C.")];
+        let three = vec![S("A"), S("B"), S("C")];
+
+        assert_eq!(ArtistJoin::Comma.join(&two), "Dani J, Caluu C.");
+        assert_eq!(ArtistJoin::Ampersand.join(&two), "Dani J & Caluu C.");
+        assert_eq!(ArtistJoin::List.join(&two), "Dani J & Caluu C.");
+        assert_eq!(ArtistJoin::Feat.join(&two), "Dani J feat. Caluu C.");
+
+        assert_eq!(ArtistJoin::Comma.join(&three), "A, B, C");
+        assert_eq!(ArtistJoin::Ampersand.join(&three), "A & B & C");
+        assert_eq!(ArtistJoin::List.join(&three), "A, B & C");
+        assert_eq!(ArtistJoin::Feat.join(&three), "A feat. B & C");
+
+        assert_eq!(ArtistJoin::Feat.join(&[S("A")]), "A");
+        assert_eq!(ArtistJoin::List.join(&[]), "");
+    }
+
+    #[test]
+    fn test_display_tags() {
+        let tags = btreemap! {
+            S("albumartist") => MetaVal::Many(vec![S("A"), S("B")]),
+            S("artist") => MetaVal::Many(vec![S("A"), S("B"), S("C")]),
+            S("composer") => MetaVal::Many(vec![S("X"), S("Y")]),
+            S("title") => MetaVal::One(S("Title")),
+        };
+
+        let display = display_tags(&tags, ArtistJoin::List);
+        assert_eq!(display["artist"], MetaVal::One(S("A, B & C")));
+        assert_eq!(display["albumartist"], MetaVal::One(S("A & B")));
+        assert_eq!(display["composer"], tags["composer"]);
+        assert_eq!(display["title"], tags["title"]);
+
+        let mut tags = tags;
+        tags.insert(S("artistsort"), MetaVal::One(S("C, The")));
+        let display = display_tags(&tags, ArtistJoin::List);
+        assert_eq!(display["artist"], MetaVal::One(S("C, The")));
+
+        tags.insert(S("displayartist"), MetaVal::One(S("A with B and C")));
+        let display = display_tags(&tags, ArtistJoin::List);
+        assert_eq!(display["artist"], MetaVal::One(S("A with B and C")));
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::plan::Collision;
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while processing an album. Each variant
+/// carries enough context (file path, JSON position, track number) to point
+/// the user at the offending input.
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// No source directory was given on the command line.
+    MissingSourceDir,
+    /// Reading or writing a file or directory failed.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Reading or writing the metadata blocks of a FLAC file failed.
+    Tag {
+        path: PathBuf,
+        source: metaflac::Error,
+    },
+    /// Decoding the audio of a FLAC file failed.
+    Decode {
+        path: PathBuf,
+        source: claxon::Error,
+    },
+    /// The audio of a FLAC file cannot be analyzed.
+    UnsupportedFormat { path: PathBuf, reason: String },
+    /// A metadata file is not valid JSON, or does not have the expected shape.
+    Json {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        source: serde_json::Error,
+    },
+    /// A FLAC file does not have exactly one numeric `tracknumber` tag.
+    InvalidTrackNumber { path: PathBuf, reason: String },
+    /// A FLAC file has a track number outside of its disc, or one that was
+    /// already taken by another file.
+    UnexpectedTrackNumber {
+        path: PathBuf,
+        disc: usize,
+        track: usize,
+    },
+    /// Some track numbers of a disc are not used by any file.
+    MissingTrackNumbers { disc: usize, tracks: Vec<usize> },
+    /// A FLAC file has a `discnumber` tag that is not a disc number.
+    InvalidDiscNumber { path: PathBuf, reason: String },
+    /// Some discs of the album have no files.
+    MissingDiscs { discs: Vec<usize> },
+    /// The number of track blocks does not match the number of tracks.
+    TrackCount { tracks: usize, track_blocks: usize },
+    /// The number of disc blocks does not match the number of discs.
+    DiscCount { discs: usize, disc_blocks: usize },
+    /// A track block is missing a key that is required to process the track.
+    /// The track is given by its position, such as `5` or `2-5`.
+    MissingKey { track: String, key: String },
+    /// The album block is missing a key that is used by the output
+    /// directory template.
+    MissingAlbumKey { key: String },
+    /// An image file cannot be embedded as a picture.
+    Image { path: PathBuf, reason: String },
+    /// No cover image was found in a directory.
+    MissingCover { dir: PathBuf },
+    /// An output directory template was given without a directory to
+    /// render it in.
+    MissingOutputDir,
+    /// An output directory cannot be written to.
+    OutputDir { dir: PathBuf, reason: String },
+    /// Output files would overwrite each other, or existing files.
+    Collisions { collisions: Vec<Collision> },
+    /// The worker threads for loudness analysis could not be started.
+    ThreadPool(rayon::ThreadPoolBuildError),
+    /// Stored ReplayGain values do not match a fresh measurement.
+    Mismatches { count: usize },
+}
+
+impl Error {
+    pub fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn tag(path: &Path) -> impl FnOnce(metaflac::Error) -> Self + '_ {
+        move |source| Self::Tag {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn decode(path: &Path) -> impl FnOnce(claxon::Error) -> Self + '_ {
+        move |source| Self::Decode {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn json(path: &Path) -> impl FnOnce(serde_json::Error) -> Self + '_ {
+        move |source| Self::Json {
+            path: path.to_path_buf(),
+            line: source.line(),
+            column: source.column(),
+            source,
+        }
+    }
+
+    /// The process exit code for this error. Each class of error has its own
+    /// code, so that scripts can tell them apart. Code 2 is used by `clap`
+    /// for invalid command line arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Mismatches { .. } => 1,
+            Self::MissingSourceDir => 2,
+            Self::Io { .. } => 3,
+            Self::Tag { .. } => 4,
+            Self::Decode { .. } | Self::UnsupportedFormat { .. } => 5,
+            Self::Json { .. } => 6,
+            Self::InvalidTrackNumber { .. }
+            | Self::UnexpectedTrackNumber { .. }
+            | Self::MissingTrackNumbers { .. }
+            | Self::InvalidDiscNumber { .. }
+            | Self::MissingDiscs { .. } => 7,
+            Self::TrackCount { .. }
+            | Self::DiscCount { .. }
+            | Self::MissingKey { .. }
+            | Self::MissingAlbumKey { .. } => 8,
+            Self::ThreadPool(..) => 9,
+            Self::Image { .. } | Self::MissingCover { .. } => 10,
+            Self::MissingOutputDir | Self::OutputDir { .. } | Self::Collisions { .. } => 11,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSourceDir => write!(f, "no source directory given"),
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Tag { path, source } => {
+                write!(
+                    f,
+                    "{}: unable to access FLAC tags: {}",
+                    path.display(),
+                    source
+                )
+            }
+            Self::Decode { path, source } => {
+                write!(
+                    f,
+                    "{}: unable to decode FLAC audio: {}",
+                    path.display(),
+                    source
+                )
+            }
+            Self::UnsupportedFormat { path, reason } => {
+                write!(f, "{}: unable to analyze audio: {}", path.display(), reason)
+            }
+            Self::Json {
+                path,
+                line,
+                column,
+                source,
+            } => {
+                // The position is reported separately, so only the message of
+                // the underlying error is shown.
+                let message = source.to_string();
+                let message = message
+                    .rsplit_once(" at line ")
+                    .map_or(message.as_str(), |(message, _)| message);
+
+                write!(f, "{}:{}:{}: {}", path.display(), line, column, message)
+            }
+            Self::InvalidTrackNumber { path, reason } => {
+                write!(f, "{}: invalid track number: {}", path.display(), reason)
+            }
+            Self::UnexpectedTrackNumber { path, disc, track } => write!(
+                f,
+                "{}: track number {} on disc {} is out of range or used by another file",
+                path.display(),
+                track,
+                disc
+            ),
+            Self::MissingTrackNumbers { disc, tracks } => {
+                let tracks = tracks.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "no files found for track number(s) {} on disc {}",
+                    tracks.join(", "),
+                    disc
+                )
+            }
+            Self::InvalidDiscNumber { path, reason } => {
+                write!(f, "{}: invalid disc number: {}", path.display(), reason)
+            }
+            Self::MissingDiscs { discs } => {
+                let discs = discs.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+                write!(f, "no files found for disc(s) {}", discs.join(", "))
+            }
+            Self::TrackCount {
+                tracks,
+                track_blocks,
+            } => write!(
+                f,
+                "found {} track(s), but the track metadata has {} block(s)",
+                tracks, track_blocks
+            ),
+            Self::DiscCount { discs, disc_blocks } => write!(
+                f,
+                "found {} disc(s), but the disc metadata has {} block(s)",
+                discs, disc_blocks
+            ),
+            Self::MissingKey { track, key } => {
+                write!(f, "track {}: metadata has no '{}' key", track, key)
+            }
+            Self::MissingAlbumKey { key } => {
+                write!(f, "album metadata has no '{}' key", key)
+            }
+            Self::Image { path, reason } => {
+                write!(f, "{}: unable to embed image: {}", path.display(), reason)
+            }
+            Self::MissingCover { dir } => {
+                write!(f, "{}: no cover image found", dir.display())
+            }
+            Self::MissingOutputDir => write!(
+                f,
+                "an output directory template needs an output directory to render it in"
+            ),
+            Self::OutputDir { dir, reason } => write!(f, "{}: {}", dir.display(), reason),
+            Self::Collisions { collisions } => {
+                writeln!(
+                    f,
+                    "found {} output file name collision(s), no files were modified:",
+                    collisions.len()
+                )?;
+                for collision in collisions {
+                    writeln!(f, "  {}", collision)?;
+                }
+                write!(f, "use --on-collision to number or overwrite them instead")
+            }
+            Self::ThreadPool(source) => write!(f, "unable to start worker threads: {}", source),
+            Self::Mismatches { count } => {
+                write!(f, "found {} mismatched ReplayGain value(s)", count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Tag { source, .. } => Some(source),
+            Self::Decode { source, .. } => Some(source),
+            Self::Json { source, .. } => Some(source),
+            Self::ThreadPool(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_error__json_position() {
+        let path = Path::new("album.json");
+        let source = serde_json::from_str::<serde_json::Value>("{\n  \"album\": ,\n}").unwrap_err();
+        let error = Error::json(path)(source);
+
+        match &error {
+            Error::Json { line, column, .. } => assert_eq!((*line, *column), (2, 12)),
+            _ => panic!("expected a JSON error"),
+        }
+
+        assert_eq!(error.to_string(), "album.json:2:12: expected value");
+        assert_eq!(error.exit_code(), 6);
+    }
+}
@@ -1,72 +1,78 @@
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::io::{IsTerminal, Read, Write};
+use std::path::PathBuf;
 
 use metaflac::Tag;
 
 pub(crate) struct Track {
     pub index: usize,
+    /// The disc of the track, for releases with more than one disc or a lone
+    /// disc that is not disc 1.
+    pub disc: Option<usize>,
     pub path: PathBuf,
     pub tag: Tag,
 }
 
+impl Track {
+    /// The position of the track, prefixed with its disc number on
+    /// multi-disc releases (e.g. `5` or `2-5`).
+    pub fn position(&self) -> String {
+        format_position(self.disc, self.index)
+    }
+}
+
+/// Formats a track number, prefixed with the disc number if there is one.
+pub(crate) fn format_position(disc_num: Option<usize>, track_num: usize) -> String {
+    match disc_num {
+        Some(disc_num) => format!("{}-{}", disc_num, track_num),
+        None => track_num.to_string(),
+    }
+}
+
 /// Pauses the program, and outputs a prompt for the user to
-/// press Enter to continue.
-pub(crate) fn pause() {
-    let mut stdout = std::io::stdout();
+/// press Enter to continue. The prompt goes to stderr, so that it does not
+/// mix with output on stdout, and is skipped when stdin is not a terminal.
+pub(crate) fn pause() -> std::io::Result<()> {
     let mut stdin = std::io::stdin();
-    write!(stdout, "Press <Enter> to continue...").unwrap();
-    stdout.flush().unwrap();
-    stdin.read(&mut [0u8]).unwrap();
+    if !stdin.is_terminal() {
+        return Ok(());
+    }
+
+    let mut stderr = std::io::stderr();
+    write!(stderr, "Press <Enter> to continue...")?;
+    stderr.flush()?;
+    stdin.read(&mut [0u8]).map(|_| ())
 }
 
-/// Attempts to pull a single element from an iterator. Panics if there are
-/// zero elements, or if there is more than more element.
-pub(crate) fn expect_one<T, I: IntoIterator<Item = T>>(it: I) -> T {
+/// Attempts to pull a single element from an iterator. Returns `None` if
+/// there are zero elements, or if there is more than one element.
+pub(crate) fn expect_one<T, I: IntoIterator<Item = T>>(it: I) -> Option<T> {
     let mut it = it.into_iter();
     let first = it.next();
     let second = it.next();
 
     match (first, second) {
-        (Some(e), None) => e,
-        _ => panic!("did not find exactly one value"),
+        (Some(e), None) => Some(e),
+        _ => None,
     }
 }
 
-pub(crate) fn calculate_gain(output_dir: &Path, temp_dir_path: &Path) {
-    // Run `bs1770gain` as an external command.
-    // This will/should also copy the tracks to their final destination directory.
-    let status = Command::new("bs1770gain")
-        .arg("--replaygain")
-        .arg("-irt")
-        .arg("--output")
-        .arg(output_dir.as_os_str())
-        .arg(temp_dir_path.as_os_str())
-        .status()
-        .unwrap();
-
-    assert!(status.success());
-}
-
-/// Generates an output filename for a track once it is finished processing.
+/// Generates the default output filename for a track, without the extension
+/// and before sanitization.
 pub(crate) fn generate_output_file_name(
+    disc_num: Option<usize>,
     track_num: usize,
     track_padding: usize,
     display_artist: &str,
     display_title: &str,
-    ext: &str,
 ) -> String {
-    let mut output_file_name = format!(
-        "{:0width$}. {} - {}.{}",
+    let disc_prefix = disc_num.map(|d| format!("{}-", d)).unwrap_or_default();
+
+    format!(
+        "{}{:0width$}. {} - {}",
+        disc_prefix,
         track_num,
         display_artist,
         display_title,
-        ext,
         width = track_padding,
-    );
-
-    // Fixing bug with fields that have path separators embedded in them.
-    output_file_name.retain(|c| c != '/');
-
-    output_file_name
+    )
 }
@@ -1,16 +1,98 @@
 use std::fmt::Display;
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::str::FromStr;
 
 use bs1770::{Power, Windows100ms};
 use claxon::FlacReader;
+use rayon::prelude::*;
 
+use crate::cache::{self, LoudnessCache};
+use crate::error::{Error, Result};
 use crate::helpers::Track;
+
+/// Oversampling factor used for true peak measurement.
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+
+/// Number of taps in the interpolation filter used for true peak measurement.
+const TRUE_PEAK_TAPS: usize = 49;
+
+/// Number of input samples that the interpolation filter spans.
+const TRUE_PEAK_HISTORY: usize = TRUE_PEAK_TAPS.div_ceil(TRUE_PEAK_OVERSAMPLING);
+
+/// Number of 100ms windows in a momentary loudness window (400ms).
+const MOMENTARY_WINDOWS: usize = 4;
+
+/// Number of 100ms windows in a short-term loudness window (3s).
+const SHORT_TERM_WINDOWS: usize = 30;
+
+/// Absolute gate used for loudness range measurement, in LUFS.
+const RANGE_ABSOLUTE_GATE_LKFS: f32 = -70.0;
+
+/// Relative gate used for loudness range measurement, in LU.
+const RANGE_RELATIVE_GATE_LU: f32 = -20.0;
+
+/// Bit depths allowed by the FLAC format.
+const SUPPORTED_BITS_PER_SAMPLE: RangeInclusive<u32> = 4..=32;
+
+/// Sample rates that loudness can be measured at. The K-weighting filter is
+/// only defined for sample rates well above its 1.7 kHz shelf frequency.
+const SUPPORTED_SAMPLE_RATES: RangeInclusive<u32> = 8_000..=384_000;
+
+/// BS.1770 weighting for surround channels (+1.5 dB).
+const SURROUND_WEIGHT: f32 = 1.41;
+
+/// The target loudness that gains are calculated against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReferenceLevel {
+    /// -18 LUFS, as used by ReplayGain 2.0.
+    ReplayGain2,
+    /// -23 LUFS, as used by EBU R128.
+    EbuR128,
+    /// A custom loudness, in LUFS.
+    Custom(f32),
+}
+
+impl ReferenceLevel {
+    pub fn lkfs(&self) -> f32 {
+        match self {
+            Self::ReplayGain2 => -18.0,
+            Self::EbuR128 => -23.0,
+            Self::Custom(lkfs) => *lkfs,
+        }
+    }
+}
+
+impl FromStr for ReferenceLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rg2" => Ok(Self::ReplayGain2),
+            "r128" => Ok(Self::EbuR128),
+            _ => s
+                .trim_end_matches("LUFS")
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|lkfs| lkfs.is_finite())
+                .map(Self::Custom)
+                .ok_or_else(|| String::from("expected 'rg2', 'r128', or a loudness in LUFS")),
+        }
+    }
+}
+
 pub(crate) struct Loudness(Power);
 
 impl Loudness {
-    fn lkfs(&self) -> f32 {
+    pub fn lkfs(&self) -> f32 {
         self.0.loudness_lkfs()
     }
+
+    /// The gain in dB needed to bring this loudness to the reference level.
+    pub fn gain(&self, reference: ReferenceLevel) -> f32 {
+        reference.lkfs() - self.lkfs()
+    }
 }
 
 impl Display for Loudness {
@@ -19,98 +101,795 @@ impl Display for Loudness {
     }
 }
 
-pub(crate) struct LoudnessAnalyzer {
-    windows: Windows100ms<Vec<Power>>,
+/// Tracks the sample peak and true peak of a single channel.
+///
+/// The true peak is measured as described in ITU-R BS.1770-4 Annex 2, by
+/// upsampling 4x with a polyphase FIR interpolation filter (a Hann-windowed
+/// sinc) and taking the highest absolute value of the upsampled signal.
+#[derive(Clone)]
+struct ChannelPeakMeter {
+    coeffs: [f32; TRUE_PEAK_TAPS],
+    history: [f32; TRUE_PEAK_HISTORY],
+    pos: usize,
+    sample_peak: f32,
+    true_peak: f32,
 }
 
-impl LoudnessAnalyzer {
-    pub fn new() -> Self {
+impl ChannelPeakMeter {
+    fn new() -> Self {
+        let mut coeffs = [0.0; TRUE_PEAK_TAPS];
+        let center = (TRUE_PEAK_TAPS - 1) as f64 / 2.0;
+
+        for (i, coeff) in coeffs.iter_mut().enumerate() {
+            let m = (i as f64 - center) * std::f64::consts::PI / TRUE_PEAK_OVERSAMPLING as f64;
+            let sinc = if m == 0.0 { 1.0 } else { m.sin() / m };
+            let window = 0.5
+                * (1.0
+                    - (2.0 * std::f64::consts::PI * i as f64 / (TRUE_PEAK_TAPS - 1) as f64).cos());
+            *coeff = (sinc * window) as f32;
+        }
+
+        // Normalize each phase to unity gain at DC.
+        for phase in 0..TRUE_PEAK_OVERSAMPLING {
+            let sum: f32 = coeffs[phase..].iter().step_by(TRUE_PEAK_OVERSAMPLING).sum();
+            for coeff in coeffs[phase..].iter_mut().step_by(TRUE_PEAK_OVERSAMPLING) {
+                *coeff /= sum;
+            }
+        }
+
         Self {
-            windows: Windows100ms::new(),
+            coeffs,
+            history: [0.0; TRUE_PEAK_HISTORY],
+            pos: 0,
+            sample_peak: 0.0,
+            true_peak: 0.0,
         }
     }
 
-    pub fn reset(&mut self) {
-        *self = Self::new();
+    fn push(&mut self, samples: impl Iterator<Item = f32>) {
+        for sample in samples {
+            self.sample_peak = self.sample_peak.max(sample.abs());
+
+            self.history[self.pos] = sample;
+
+            // Each phase of the filter produces one of the interpolated
+            // samples between the newest input sample and the one before it.
+            for phase in 0..TRUE_PEAK_OVERSAMPLING {
+                let mut acc = 0.0;
+                for (k, coeff) in self.coeffs[phase..]
+                    .iter()
+                    .step_by(TRUE_PEAK_OVERSAMPLING)
+                    .enumerate()
+                {
+                    let idx = (self.pos + TRUE_PEAK_HISTORY - k) % TRUE_PEAK_HISTORY;
+                    acc += coeff * self.history[idx];
+                }
+                self.true_peak = self.true_peak.max(acc.abs());
+            }
+
+            self.pos = (self.pos + 1) % TRUE_PEAK_HISTORY;
+        }
     }
+}
 
-    pub fn calculate_track_loudness(&mut self, track_path: &Path) -> Loudness {
-        let mut reader = FlacReader::open(&track_path).unwrap();
+/// Returns the BS.1770 channel weightings for the given number of channels,
+/// using the channel orderings defined by the FLAC format. Surround channels
+/// are weighted at +1.5 dB, and the LFE channel is excluded entirely.
+fn channel_weights(num_channels: u32) -> Option<&'static [f32]> {
+    let weights: &'static [f32] = match num_channels {
+        // Mono.
+        1 => &[1.0],
+        // L, R.
+        2 => &[1.0, 1.0],
+        // L, R, C.
+        3 => &[1.0, 1.0, 1.0],
+        // FL, FR, BL, BR.
+        4 => &[1.0, 1.0, SURROUND_WEIGHT, SURROUND_WEIGHT],
+        // FL, FR, FC, BL, BR.
+        5 => &[1.0, 1.0, 1.0, SURROUND_WEIGHT, SURROUND_WEIGHT],
+        // FL, FR, FC, LFE, BL, BR.
+        6 => &[1.0, 1.0, 1.0, 0.0, SURROUND_WEIGHT, SURROUND_WEIGHT],
+        // FL, FR, FC, LFE, BC, SL, SR.
+        7 => &[
+            1.0,
+            1.0,
+            1.0,
+            0.0,
+            SURROUND_WEIGHT,
+            SURROUND_WEIGHT,
+            SURROUND_WEIGHT,
+        ],
+        // FL, FR, FC, LFE, BL, BR, SL, SR.
+        8 => &[
+            1.0,
+            1.0,
+            1.0,
+            0.0,
+            SURROUND_WEIGHT,
+            SURROUND_WEIGHT,
+            SURROUND_WEIGHT,
+            SURROUND_WEIGHT,
+        ],
+        _ => return None,
+    };
 
-        let streaminfo = reader.streaminfo();
+    Some(weights)
+}
+
+/// Combines the 100ms windows of each channel into a single set of windows,
+/// by summing the channel powers according to their weightings.
+fn reduce_channels(
+    meters: &[bs1770::ChannelLoudnessMeter],
+    weights: &[f32],
+) -> Windows100ms<Vec<Power>> {
+    let channel_windows = meters
+        .iter()
+        .map(|m| m.as_100ms_windows().inner)
+        .collect::<Vec<_>>();
+    let num_windows = channel_windows.iter().map(|w| w.len()).min().unwrap_or(0);
+
+    Windows100ms {
+        inner: (0..num_windows)
+            .map(|i| {
+                Power(
+                    channel_windows
+                        .iter()
+                        .zip(weights)
+                        .map(|(w, weight)| weight * w[i].0)
+                        .sum(),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Converts a loudness in LUFS to the equivalent mean power.
+fn lkfs_to_power(lkfs: f32) -> f32 {
+    10.0_f32.powf((lkfs + 0.691) / 10.0)
+}
+
+/// Calculates the mean power of every sliding window spanning `len` 100ms
+/// windows, advancing by 100ms at a time.
+fn sliding_powers(windows: &[Power], len: usize) -> impl Iterator<Item = f32> + '_ {
+    windows
+        .windows(len)
+        .map(move |w| (w.iter().map(|p| p.0 as f64).sum::<f64>() / len as f64) as f32)
+}
+
+/// Finds the loudest sliding window spanning `len` 100ms windows. If there
+/// are not enough windows to fill one, the result is silence.
+fn max_sliding_loudness(windows: &[Power], len: usize) -> Loudness {
+    let max_power = sliding_powers(windows, len).fold(0.0, f32::max);
+
+    Loudness(Power(max_power))
+}
+
+/// Calculates the loudness range (LRA) in LU, as specified by EBU Tech 3342.
+fn loudness_range(windows: &[Power]) -> f32 {
+    let absolute_gate = lkfs_to_power(RANGE_ABSOLUTE_GATE_LKFS);
+
+    let short_term_powers = sliding_powers(windows, SHORT_TERM_WINDOWS)
+        .filter(|p| *p > absolute_gate)
+        .collect::<Vec<_>>();
+
+    if short_term_powers.is_empty() {
+        return 0.0;
+    }
+
+    let mean_power =
+        short_term_powers.iter().map(|p| *p as f64).sum::<f64>() / short_term_powers.len() as f64;
+    let relative_gate = mean_power as f32 * 10.0_f32.powf(RANGE_RELATIVE_GATE_LU / 10.0);
+
+    let mut short_term_loudnesses = short_term_powers
+        .into_iter()
+        .filter(|p| *p > relative_gate)
+        .map(|p| Power(p).loudness_lkfs())
+        .collect::<Vec<_>>();
+
+    if short_term_loudnesses.is_empty() {
+        return 0.0;
+    }
+
+    short_term_loudnesses.sort_by(f32::total_cmp);
+
+    let percentile = |p: f32| {
+        let index = ((short_term_loudnesses.len() - 1) as f32 * p).round() as usize;
+        short_term_loudnesses[index]
+    };
+
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Loudness and peak levels measured over a track or an album. Peak values
+/// are linear, relative to digital full scale.
+pub(crate) struct Measurement {
+    pub loudness: Loudness,
+    /// Loudness range (LRA) in LU.
+    pub range: f32,
+    pub max_momentary: Loudness,
+    pub max_short_term: Loudness,
+    pub sample_peak: f32,
+    pub true_peak: f32,
+}
+
+impl Measurement {
+    fn from_windows(windows: &[Power], sample_peak: f32, true_peak: f32) -> Self {
+        let gated_power = bs1770::gated_mean(Windows100ms { inner: windows }).unwrap_or(Power(0.0));
+
+        Self {
+            loudness: Loudness(gated_power),
+            range: loudness_range(windows),
+            max_momentary: max_sliding_loudness(windows, MOMENTARY_WINDOWS),
+            max_short_term: max_sliding_loudness(windows, SHORT_TERM_WINDOWS),
+            sample_peak,
+            true_peak,
+        }
+    }
+
+    /// The peak level to store in tags. This is the true peak, but never less
+    /// than the sample peak, since the interpolation filter can undershoot it
+    /// by a tiny amount.
+    pub fn peak(&self) -> f32 {
+        self.true_peak.max(self.sample_peak)
+    }
+}
+
+/// The raw data gathered by decoding a single track: its 100ms windows,
+/// already reduced across channels, and its peak levels.
+pub(crate) struct TrackScan {
+    pub windows: Windows100ms<Vec<Power>>,
+    pub sample_peak: f32,
+    pub true_peak: f32,
+}
+
+/// Incrementally scans the decoded samples of a track.
+struct TrackScanner {
+    normalizer: f32,
+    weights: &'static [f32],
+    meters: Vec<bs1770::ChannelLoudnessMeter>,
+    peak_meters: Vec<ChannelPeakMeter>,
+}
+
+impl TrackScanner {
+    /// Creates a scanner for audio in the given format, or returns the reason
+    /// why the format cannot be analyzed.
+    fn new(
+        sample_rate: u32,
+        channels: u32,
+        bits_per_sample: u32,
+    ) -> std::result::Result<Self, String> {
+        if !SUPPORTED_BITS_PER_SAMPLE.contains(&bits_per_sample) {
+            return Err(format!("unsupported bits per sample: {}", bits_per_sample));
+        }
+        if !SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+            return Err(format!("unsupported sample rate: {}", sample_rate));
+        }
+
+        let weights = channel_weights(channels)
+            .ok_or_else(|| format!("unsupported channel count: {}", channels))?;
 
         // The maximum amplitude is 1 << (bits per sample - 1), because one bit
-        // is the sign bit.
-        let normalizer = 1.0 / (1_u64 << (streaminfo.bits_per_sample - 1)) as f32;
+        // is the sign bit. This is a power of two, so it is exact as a float
+        // for every supported bit depth.
+        let normalizer = 1.0 / (1_u64 << (bits_per_sample - 1)) as f32;
 
-        assert!(streaminfo.channels == 2);
+        Ok(Self {
+            normalizer,
+            weights,
+            meters: vec![bs1770::ChannelLoudnessMeter::new(sample_rate); channels as usize],
+            peak_meters: vec![ChannelPeakMeter::new(); channels as usize],
+        })
+    }
 
-        let mut meters = vec![
-            bs1770::ChannelLoudnessMeter::new(streaminfo.sample_rate);
-            streaminfo.channels as usize
-        ];
+    /// Feeds the next samples of a single channel into the scanner. Every
+    /// channel must be fed the same number of samples.
+    fn push_channel(&mut self, ch: usize, samples: &[i32]) {
+        let normalizer = self.normalizer;
+
+        // Converting to `f32` drops the lowest bits of samples wider than 24
+        // bits, which is far below the precision of any loudness value.
+        self.meters[ch].push(samples.iter().map(|s| *s as f32 * normalizer));
+        self.peak_meters[ch].push(samples.iter().map(|s| *s as f32 * normalizer));
+    }
+
+    fn finish(self) -> TrackScan {
+        TrackScan {
+            windows: reduce_channels(&self.meters, self.weights),
+            sample_peak: self
+                .peak_meters
+                .iter()
+                .map(|m| m.sample_peak)
+                .fold(0.0, f32::max),
+            true_peak: self
+                .peak_meters
+                .iter()
+                .map(|m| m.true_peak)
+                .fold(0.0, f32::max),
+        }
+    }
+}
+
+impl TrackScan {
+    pub fn from_path(track_path: &Path) -> Result<Self> {
+        let mut reader = FlacReader::open(track_path).map_err(Error::decode(track_path))?;
+
+        let streaminfo = reader.streaminfo();
+
+        let mut scanner = TrackScanner::new(
+            streaminfo.sample_rate,
+            streaminfo.channels,
+            streaminfo.bits_per_sample,
+        )
+        .map_err(|reason| Error::UnsupportedFormat {
+            path: track_path.to_path_buf(),
+            reason,
+        })?;
 
         let mut blocks = reader.blocks();
         let mut buffer = Vec::new();
 
-        while let Some(block) = blocks.read_next_or_eof(buffer).unwrap() {
-            for (ch, meter) in meters.iter_mut().enumerate() {
-                meter.push(
-                    block
-                        .channel(ch as u32)
-                        .iter()
-                        .map(|s| *s as f32 * normalizer),
-                );
+        while let Some(block) = blocks
+            .read_next_or_eof(buffer)
+            .map_err(Error::decode(track_path))?
+        {
+            for ch in 0..block.channels() {
+                scanner.push_channel(ch as usize, block.channel(ch));
             }
             buffer = block.into_buffer();
         }
 
-        let zipped: Windows100ms<Vec<Power>> =
-            bs1770::reduce_stereo(meters[0].as_100ms_windows(), meters[1].as_100ms_windows());
-        let gated_power = bs1770::gated_mean(zipped.as_ref()).unwrap_or(Power(0.0));
+        Ok(scanner.finish())
+    }
+}
 
-        // Update the album loudness window.
-        self.windows.inner.extend(zipped.inner);
+pub(crate) struct LoudnessAnalyzer {
+    windows: Windows100ms<Vec<Power>>,
+    sample_peak: f32,
+    true_peak: f32,
+}
 
-        Loudness(gated_power)
+impl LoudnessAnalyzer {
+    pub fn new() -> Self {
+        Self {
+            windows: Windows100ms::new(),
+            sample_peak: 0.0,
+            true_peak: 0.0,
+        }
     }
 
-    pub fn calculate_album_loudness(&self) -> Loudness {
-        let gated_power = bs1770::gated_mean(self.windows.as_ref()).unwrap_or(Power(0.0));
+    /// Measures a previously scanned track, and adds its data to the album.
+    /// Album results only depend on the order in which scans are added, not
+    /// on the order in which they were produced.
+    pub fn add_track_scan(&mut self, scan: TrackScan) -> Measurement {
+        let TrackScan {
+            windows,
+            sample_peak,
+            true_peak,
+        } = scan;
+
+        let measurement = Measurement::from_windows(&windows.inner, sample_peak, true_peak);
+
+        // Update the album loudness window and peaks. Since the windows have
+        // already been reduced across channels, tracks with differing channel
+        // layouts can be aggregated together.
+        self.windows.inner.extend(windows.inner);
+        self.sample_peak = self.sample_peak.max(sample_peak);
+        self.true_peak = self.true_peak.max(true_peak);
+
+        measurement
+    }
 
-        Loudness(gated_power)
+    pub fn measure_album(&self) -> Measurement {
+        Measurement::from_windows(&self.windows.inner, self.sample_peak, self.true_peak)
     }
 }
 
 pub(crate) struct ScannedTrack {
-    track: Track,
-    loudness: Loudness,
+    pub track: Track,
+    pub measurement: Measurement,
 }
 
 pub(crate) struct AnalysisOutput {
-    scanned_tracks: Vec<ScannedTrack>,
-    album_loudness: Loudness,
+    pub scanned_tracks: Vec<ScannedTrack>,
+    pub album: Measurement,
 }
 
-pub(crate) fn analyze_tracks(tracks: Vec<Track>) -> AnalysisOutput {
-    // Scan tracks for loudness.
+/// Analyzes the loudness of a set of tracks, and of the album they form.
+/// Tracks are decoded concurrently on up to `jobs` threads (or one per CPU
+/// if `jobs` is 0), but are always aggregated in their original order.
+///
+/// If a cache is given, tracks whose audio was previously scanned are not
+/// decoded again, and new scans are added to the cache.
+pub(crate) fn analyze_tracks(
+    tracks: Vec<Track>,
+    jobs: usize,
+    mut cache: Option<&mut LoudnessCache>,
+) -> Result<AnalysisOutput> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(Error::ThreadPool)?;
+
+    let track_paths = tracks.iter().map(|t| t.path.as_path()).collect::<Vec<_>>();
+
+    // Look up any previous scans of the tracks.
+    let signatures = match cache {
+        Some(_) => track_paths
+            .iter()
+            .map(|track_path| cache::audio_signature(track_path))
+            .collect::<Vec<_>>(),
+        None => vec![None; track_paths.len()],
+    };
+    let cached_scans = signatures
+        .iter()
+        .map(|signature| {
+            let cache = cache.as_ref()?;
+            cache.get(signature.as_ref()?)
+        })
+        .collect::<Vec<_>>();
+
+    // Scan the remaining tracks for loudness.
+    let scans = pool.install(|| {
+        track_paths
+            .par_iter()
+            .zip(cached_scans)
+            .map(|(track_path, cached_scan)| match cached_scan {
+                Some(scan) => {
+                    eprintln!("Using cached loudness: {}", track_path.display());
+                    Ok(scan)
+                }
+                None => {
+                    eprintln!("Analyzing loudness: {}", track_path.display());
+                    TrackScan::from_path(track_path)
+                }
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    if let Some(cache) = cache.as_mut() {
+        for (signature, scan) in signatures.into_iter().zip(&scans) {
+            if let Some(signature) = signature {
+                cache.insert(signature, scan);
+            }
+        }
+    }
+
     let mut loudness_analyzer = LoudnessAnalyzer::new();
     let scanned_tracks = tracks
         .into_iter()
-        .map(|track| {
-            let track_loudness = loudness_analyzer.calculate_track_loudness(&track.path);
+        .zip(scans)
+        .map(|(track, scan)| {
+            let measurement = loudness_analyzer.add_track_scan(scan);
 
-            ScannedTrack {
-                track,
-                loudness: track_loudness,
-            }
+            ScannedTrack { track, measurement }
         })
         .collect::<Vec<_>>();
 
-    let album_loudness = loudness_analyzer.calculate_album_loudness();
+    let album = loudness_analyzer.measure_album();
 
-    AnalysisOutput {
+    Ok(AnalysisOutput {
         scanned_tracks,
-        album_loudness,
+        album,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn sine(amplitude: f64, freq: f64, phase: f64, sample_rate: f64, len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|n| {
+                let t = n as f64 / sample_rate;
+                amplitude * (2.0 * std::f64::consts::PI * freq * t + phase).sin()
+            })
+            .collect()
+    }
+
+    /// Generates a sine wave with the given peak level in dBFS, as floating
+    /// point samples in the range -1.0..=1.0.
+    fn sine_dbfs(level_dbfs: f64, freq: f64, sample_rate: u32, seconds: f64) -> Vec<f64> {
+        let amplitude = 10.0_f64.powf(level_dbfs / 20.0);
+        let len = (sample_rate as f64 * seconds).round() as usize;
+
+        sine(amplitude, freq, 0.0, sample_rate as f64, len)
+    }
+
+    /// Generates deterministic white noise with the given peak amplitude.
+    fn noise(amplitude: f64, len: usize) -> Vec<f64> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+
+        (0..len)
+            .map(|_| {
+                // xorshift64*
+                state ^= state >> 12;
+                state ^= state << 25;
+                state ^= state >> 27;
+                let r = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+                amplitude * (r as f64 / (1_u64 << 53) as f64 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    /// Quantizes a signal to the integer samples a FLAC decoder would produce
+    /// for the given bit depth.
+    fn quantize(signal: &[f64], bits_per_sample: u32) -> Vec<i32> {
+        let full_scale = (1_i64 << (bits_per_sample - 1)) as f64;
+
+        signal
+            .iter()
+            .map(|x| {
+                (x * full_scale)
+                    .round()
+                    .clamp(-full_scale, full_scale - 1.0) as i32
+            })
+            .collect()
+    }
+
+    /// Scans a signal, played identically on every channel.
+    fn scan_signal(
+        signal: &[f64],
+        sample_rate: u32,
+        channels: u32,
+        bits_per_sample: u32,
+    ) -> TrackScan {
+        let samples = quantize(signal, bits_per_sample);
+        let mut scanner = TrackScanner::new(sample_rate, channels, bits_per_sample).unwrap();
+
+        // Feed the samples in FLAC-sized blocks.
+        for block in samples.chunks(4096) {
+            for ch in 0..channels as usize {
+                scanner.push_channel(ch, block);
+            }
+        }
+
+        scanner.finish()
+    }
+
+    fn integrated_lkfs(scan: &TrackScan) -> f32 {
+        Measurement::from_windows(&scan.windows.inner, scan.sample_peak, scan.true_peak)
+            .loudness
+            .lkfs()
+    }
+
+    /// Tolerance for measured loudness in the EBU Tech 3341 compliance tests.
+    const COMPLIANCE_TOLERANCE: f32 = 0.1;
+
+    #[test]
+    fn test_track_scanner__bit_depths() {
+        // EBU Tech 3341 test 1: a stereo 1 kHz sine at -23 dBFS measures
+        // -23 LUFS. Below 12 bits, quantization distortion of a -23 dBFS sine
+        // is too large for this to hold, which the next test accounts for.
+        for bits_per_sample in 12..=32 {
+            let signal = sine_dbfs(-23.0, 1000.0, 48000, 1.0);
+            let scan = scan_signal(&signal, 48000, 2, bits_per_sample);
+
+            let lkfs = integrated_lkfs(&scan);
+            assert!(
+                (lkfs - -23.0).abs() < COMPLIANCE_TOLERANCE,
+                "{} bits: measured {} LUFS, expected -23 LUFS",
+                bits_per_sample,
+                lkfs,
+            );
+        }
+    }
+
+    #[test]
+    fn test_track_scanner__normalization() {
+        // The same quantized signal must measure identically at every bit
+        // depth, when padded with zero bits up to 32 bits.
+        let signal = sine_dbfs(-3.0, 1000.0, 48000, 0.5);
+
+        for bits_per_sample in SUPPORTED_BITS_PER_SAMPLE {
+            let samples = quantize(&signal, bits_per_sample);
+            let padded = samples
+                .iter()
+                .map(|s| s << (32 - bits_per_sample))
+                .collect::<Vec<_>>();
+
+            let mut scanner = TrackScanner::new(48000, 2, bits_per_sample).unwrap();
+            let mut padded_scanner = TrackScanner::new(48000, 2, 32).unwrap();
+            for ch in 0..2 {
+                scanner.push_channel(ch, &samples);
+                padded_scanner.push_channel(ch, &padded);
+            }
+            let scan = scanner.finish();
+            let padded_scan = padded_scanner.finish();
+
+            assert_eq!(integrated_lkfs(&scan), integrated_lkfs(&padded_scan));
+            assert_eq!(scan.sample_peak, padded_scan.sample_peak);
+            assert_eq!(scan.true_peak, padded_scan.true_peak);
+
+            // The loudest sample of a -3 dBFS sine is within a quantization
+            // step of its peak.
+            let step = 1.0 / (1_u64 << (bits_per_sample - 1)) as f32;
+            let peak_error = (scan.sample_peak - 10.0_f32.powf(-3.0 / 20.0)).abs();
+            assert!(peak_error <= step.max(f32::EPSILON));
+        }
+    }
+
+    #[test]
+    fn test_track_scanner__sample_rates() {
+        for sample_rate in [
+            8000, 22050, 44100, 48000, 88200, 96000, 176400, 192000, 352800, 384000,
+        ] {
+            let signal = sine_dbfs(-23.0, 1000.0, sample_rate, 1.0);
+            let scan = scan_signal(&signal, sample_rate, 2, 24);
+
+            let lkfs = integrated_lkfs(&scan);
+            assert!(
+                (lkfs - -23.0).abs() < COMPLIANCE_TOLERANCE,
+                "{} Hz: measured {} LUFS, expected -23 LUFS",
+                sample_rate,
+                lkfs,
+            );
+        }
+    }
+
+    #[test]
+    fn test_track_scanner__gating() {
+        // EBU Tech 3341 test 5: a stereo 1 kHz sine at -26 dBFS for 20s,
+        // then -20 dBFS for 20.1s, then -26 dBFS for 20s measures -23 LUFS.
+        let mut signal = sine_dbfs(-26.0, 1000.0, 48000, 20.0);
+        signal.extend(sine_dbfs(-20.0, 1000.0, 48000, 20.1));
+        signal.extend(sine_dbfs(-26.0, 1000.0, 48000, 20.0));
+
+        let scan = scan_signal(&signal, 48000, 2, 16);
+
+        assert!((integrated_lkfs(&scan) - -23.0).abs() < COMPLIANCE_TOLERANCE);
+    }
+
+    #[test]
+    fn test_track_scanner__mono() {
+        // A mono signal has half the power of the same signal in stereo.
+        let signal = sine_dbfs(-20.0, 1000.0, 48000, 3.0);
+        let scan = scan_signal(&signal, 48000, 1, 16);
+
+        assert!((integrated_lkfs(&scan) - -23.01).abs() < COMPLIANCE_TOLERANCE);
+    }
+
+    #[test]
+    fn test_track_scanner__noise() {
+        // The same noise must measure the same loudness and peaks at every bit
+        // depth where quantization noise is negligible.
+        let signal = noise(0.25, 48000 * 3);
+
+        let reference = scan_signal(&signal, 48000, 2, 32);
+        let reference_lkfs = integrated_lkfs(&reference);
+
+        assert!((reference.sample_peak - 0.25).abs() < 0.001);
+        assert!(reference.true_peak >= reference.sample_peak);
+
+        for bits_per_sample in [16, 20, 24, 28] {
+            let scan = scan_signal(&signal, 48000, 2, bits_per_sample);
+
+            assert!((integrated_lkfs(&scan) - reference_lkfs).abs() < 0.01);
+            assert!((scan.sample_peak - reference.sample_peak).abs() < 0.001);
+            assert!((scan.true_peak - reference.true_peak).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_track_scanner__unsupported_bits_per_sample() {
+        let result = TrackScanner::new(48000, 2, 33);
+        assert_eq!(result.err().unwrap(), "unsupported bits per sample: 33");
+    }
+
+    #[test]
+    fn test_track_scanner__unsupported_sample_rate() {
+        let result = TrackScanner::new(768000, 2, 24);
+        assert_eq!(result.err().unwrap(), "unsupported sample rate: 768000");
+    }
+
+    #[test]
+    fn test_track_scanner__unsupported_channel_count() {
+        let result = TrackScanner::new(48000, 9, 24);
+        assert_eq!(result.err().unwrap(), "unsupported channel count: 9");
+    }
+
+    #[test]
+    fn test_reference_level__from_str() {
+        assert_eq!("rg2".parse(), Ok(ReferenceLevel::ReplayGain2));
+        assert_eq!("r128".parse(), Ok(ReferenceLevel::EbuR128));
+        assert_eq!("-16".parse(), Ok(ReferenceLevel::Custom(-16.0)));
+        assert_eq!("-14.5 LUFS".parse(), Ok(ReferenceLevel::Custom(-14.5)));
+
+        assert!("loud".parse::<ReferenceLevel>().is_err());
+        assert!("NaN".parse::<ReferenceLevel>().is_err());
+    }
+
+    fn constant_windows(lkfs: f32, count: usize) -> Vec<Power> {
+        vec![Power(lkfs_to_power(lkfs)); count]
+    }
+
+    #[test]
+    fn test_loudness_range() {
+        // One minute at -20 LUFS followed by one minute at -30 LUFS.
+        let mut windows = constant_windows(-20.0, 600);
+        windows.extend(constant_windows(-30.0, 600));
+
+        assert!((loudness_range(&windows) - 10.0).abs() < 0.01);
+
+        // A constant level has no loudness range.
+        let windows = constant_windows(-20.0, 600);
+        assert!(loudness_range(&windows).abs() < 0.01);
+
+        // Silence and signals shorter than a short-term window are gated out.
+        assert_eq!(loudness_range(&constant_windows(-80.0, 600)), 0.0);
+        assert_eq!(loudness_range(&constant_windows(-20.0, 20)), 0.0);
+    }
+
+    #[test]
+    fn test_max_sliding_loudness() {
+        // A 200ms burst at -10 LUFS in an otherwise -30 LUFS signal.
+        let mut windows = constant_windows(-30.0, 100);
+        windows.extend(constant_windows(-10.0, 2));
+        windows.extend(constant_windows(-30.0, 100));
+
+        let max_momentary = max_sliding_loudness(&windows, MOMENTARY_WINDOWS).lkfs();
+        let max_short_term = max_sliding_loudness(&windows, SHORT_TERM_WINDOWS).lkfs();
+
+        // The burst fills half of a momentary window.
+        let expected_momentary = Power((lkfs_to_power(-10.0) + lkfs_to_power(-30.0)) / 2.0);
+        assert!((max_momentary - expected_momentary.loudness_lkfs()).abs() < 0.01);
+
+        // The burst is diluted further over a short-term window.
+        assert!(max_short_term < max_momentary);
+        assert!(max_short_term > -30.0);
+    }
+
+    #[test]
+    fn test_channel_weights() {
+        assert_eq!(channel_weights(1), Some(&[1.0][..]));
+        assert_eq!(channel_weights(2), Some(&[1.0, 1.0][..]));
+
+        // The LFE channel of a 5.1 layout is excluded.
+        let weights = channel_weights(6).unwrap();
+        assert_eq!(weights[3], 0.0);
+        assert_eq!(weights[4], SURROUND_WEIGHT);
+
+        for num_channels in 1..=8 {
+            assert_eq!(
+                channel_weights(num_channels).map(|w| w.len()),
+                Some(num_channels as usize)
+            );
+        }
+
+        assert_eq!(channel_weights(0), None);
+        assert_eq!(channel_weights(9), None);
+    }
+
+    #[test]
+    fn test_channel_peak_meter__sample_peak() {
+        let mut meter = ChannelPeakMeter::new();
+        meter.push(vec![0.25, -0.5, 0.125].into_iter());
+
+        assert_eq!(meter.sample_peak, 0.5);
+    }
+
+    #[test]
+    fn test_channel_peak_meter__inter_sample_peak() {
+        // A quarter-sample-rate sine offset by 45 degrees never hits its
+        // peak on a sample, so the sample peak is ~3 dB below the true peak.
+        let samples = sine(0.5, 12000.0, std::f64::consts::FRAC_PI_4, 48000.0, 4800);
+
+        let mut meter = ChannelPeakMeter::new();
+        meter.push(samples.into_iter().map(|s| s as f32));
+
+        assert!((meter.sample_peak - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-4);
+        assert!((meter.true_peak - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_channel_peak_meter__low_frequency() {
+        let samples = sine(0.8, 997.0, 0.0, 48000.0, 48000);
+
+        let mut meter = ChannelPeakMeter::new();
+        meter.push(samples.into_iter().map(|s| s as f32));
+
+        assert!((meter.sample_peak - 0.8).abs() < 0.001);
+        assert!((meter.true_peak - 0.8).abs() < 0.005);
     }
 }
@@ -1,84 +1,185 @@
+mod cache;
+mod config;
+mod diff;
+mod display;
+mod error;
 mod helpers;
 mod loudness;
 mod metadata;
 mod opts;
+mod picture;
+mod plan;
+mod preserve;
 mod reader;
+mod report;
+mod sanitize;
+mod template;
+mod transaction;
+mod verify;
 mod writer;
 
 use std::path::Path;
 
 use clap::Parser;
 
+use crate::cache::LoudnessCache;
+use crate::config::Config;
+use crate::diff::DiffFormat;
+use crate::error::{Error, Result};
 use crate::helpers::Track;
 use crate::metadata::Metadata;
-use crate::opts::Opts;
-
-fn process_tracks(tracks: Vec<Track>, incoming_metadata: Metadata, output_dir: &Path) {
-    let Metadata {
-        album: incoming_album_block,
-        tracks: incoming_track_blocks,
-    } = incoming_metadata;
-
-    // Ensure equal numbers of tracks and track blocks.
-    assert_eq!(tracks.len(), incoming_track_blocks.len());
-
-    let total_tracks = tracks.len();
-    let num_digits = format!("{}", total_tracks).len();
-
-    {
-        let temp_dir = tempfile::tempdir().expect("unable to create temp dir");
-        let temp_dir_path = temp_dir.path();
-
-        println!("Created temp dir: {}", temp_dir_path.display());
-
-        for (track, incoming_track_block) in tracks.into_iter().zip(incoming_track_blocks) {
-            let display_artist = incoming_track_block
-                .get("artist")
-                .expect("track block did not have a 'artist' key")
-                .to_string();
-            let display_title = incoming_track_block
-                .get("title")
-                .expect("track block did not have a 'title' key")
-                .to_string();
-
-            println!("Processing input file: {}", track.path.display());
-            writer::write_tags_to_track(
-                &track,
-                total_tracks,
-                incoming_album_block.clone(),
-                incoming_track_block,
-            );
-
-            let ext = track.path.extension().unwrap().to_string_lossy();
-            let output_track_file_name = helpers::generate_output_file_name(
-                track.index,
-                num_digits,
-                &display_artist,
-                &display_title,
-                &ext,
-            );
-
-            let interim_path = temp_dir_path.join(&output_track_file_name);
-
-            println!("Moving file to temp dir: {}", output_track_file_name);
-            std::fs::rename(&track.path, &interim_path).unwrap();
+use crate::opts::{AnalysisOpts, AnalyzeOpts, CheckOpts, Command, Opts, RetagOpts};
+use crate::plan::TrackPlan;
+use crate::preserve::PreservePolicy;
+use crate::reader::TrackOrder;
+use crate::report::LoudnessReport;
+use crate::sanitize::Sanitizer;
+use crate::transaction::Transaction;
+use crate::verify::Tolerances;
+use crate::writer::{GainOptions, PictureOptions};
+
+/// Tags and renames the planned tracks, and writes the incoming metadata file
+/// next to them. All work happens on staged copies, so the source and output
+/// directories are only changed once every step has succeeded. Unless
+/// `keep_sources` is set, the source files are removed at that point.
+fn process_tracks(
+    plans: Vec<TrackPlan>,
+    incoming_metadata: &Metadata,
+    output_dir: &Path,
+    keep_sources: bool,
+    picture_options: &PictureOptions,
+    gain_options: &GainOptions,
+    analysis_opts: &AnalysisOpts,
+) -> Result<()> {
+    let mut transaction = Transaction::new(output_dir)?;
+
+    eprintln!(
+        "Created staging dir: {}",
+        transaction.staging_dir().display()
+    );
+
+    let mut staged_tracks = Vec::with_capacity(plans.len());
+
+    for plan in plans {
+        let TrackPlan {
+            track,
+            tags,
+            output_file_name,
+            output_path,
+        } = plan;
+
+        eprintln!("Processing input file: {}", track.path.display());
+
+        eprintln!("Copying file to staging dir: {}", output_file_name);
+        let staged_path = transaction.stage_copy(&track.path, output_path)?;
+        if !keep_sources {
+            transaction.remove(track.path.clone());
         }
 
-        println!("Running bs1770gain");
-        helpers::calculate_gain(&output_dir, &temp_dir_path);
+        let staged_track = Track {
+            path: staged_path,
+            ..track
+        };
+        writer::write_tags_to_track(&staged_track, tags, picture_options)?;
+
+        staged_tracks.push(staged_track);
     }
+
+    let mut loudness_cache = open_loudness_cache(analysis_opts);
+    let analysis_output =
+        loudness::analyze_tracks(staged_tracks, analysis_opts.jobs, loudness_cache.as_mut())?;
+    if let Some(loudness_cache) = loudness_cache {
+        loudness_cache.save()?;
+    }
+
+    for scanned_track in analysis_output.scanned_tracks.iter() {
+        writer::write_gain_tags(scanned_track, &analysis_output.album, gain_options)?;
+    }
+
+    // Write out the incoming metadata to the output directory.
+    let metadata_fp = transaction.stage(output_dir.join("meta.json"));
+    writer::write_output_metadata_file(&metadata_fp, incoming_metadata)?;
+
+    eprintln!("Moving files to output dir: {}", output_dir.display());
+    transaction.commit()
 }
 
-fn main() {
-    let opts = Opts::parse();
+fn open_loudness_cache(opts: &AnalysisOpts) -> Option<LoudnessCache> {
+    if opts.no_loudness_cache {
+        return None;
+    }
+
+    opts.loudness_cache
+        .clone()
+        .or_else(cache::default_cache_path)
+        .map(LoudnessCache::open)
+}
+
+fn analyze(opts: AnalyzeOpts) -> Result<()> {
+    let tracks = reader::collect_tracks(&opts.source_dir, TrackOrder::default(), false, None)?;
+
+    let mut loudness_cache = open_loudness_cache(&opts.analysis);
+    let analysis_output =
+        loudness::analyze_tracks(tracks, opts.analysis.jobs, loudness_cache.as_mut())?;
+    if let Some(loudness_cache) = loudness_cache {
+        loudness_cache.save()?;
+    }
+    let report = LoudnessReport::new(&analysis_output, opts.reference);
+
+    if opts.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("report is always serializable")
+        );
+    } else {
+        report.print_table();
+    }
+
+    Ok(())
+}
+
+fn check(opts: CheckOpts) -> Result<()> {
+    let tracks = reader::collect_tracks(&opts.source_dir, TrackOrder::default(), false, None)?;
+    let num_tracks = tracks.len();
+
+    let tolerances = Tolerances {
+        gain: opts.tolerance,
+        peak: opts.peak_tolerance,
+    };
+    let mut loudness_cache = open_loudness_cache(&opts.analysis);
+    let mismatches = verify::verify_tracks(
+        tracks,
+        &tolerances,
+        opts.analysis.jobs,
+        loudness_cache.as_mut(),
+    )?;
+    if let Some(loudness_cache) = loudness_cache {
+        loudness_cache.save()?;
+    }
+
+    for mismatch in mismatches.iter() {
+        println!("{}", mismatch);
+    }
+
+    if mismatches.is_empty() {
+        println!("ReplayGain tags of {} track(s) are correct", num_tracks);
+        Ok(())
+    } else {
+        Err(Error::Mismatches {
+            count: mismatches.len(),
+        })
+    }
+}
+
+fn retag(opts: RetagOpts) -> Result<()> {
+    let source_dir = opts.source_dir.ok_or(Error::MissingSourceDir)?;
 
     let tracks = reader::collect_tracks(
-        &opts.source_dir,
+        &source_dir,
+        opts.track_order,
         opts.emit_existing,
-        opts.emit_existing_to.as_ref().map(|p| p.as_path()),
-    );
-
-    let source_dir = opts.source_dir;
+        opts.emit_existing_to.as_deref(),
+    )?;
 
     let album_block_file = opts
         .album_block_file
@@ -86,16 +187,144 @@ fn main() {
     let track_blocks_file = opts
         .track_blocks_file
         .unwrap_or_else(|| source_dir.join("track.json"));
+    let disc_blocks_file = opts
+        .disc_blocks_file
+        .or_else(|| Some(source_dir.join("disc.json")).filter(|p| p.exists()));
 
-    // If no output directory is given, use the source directory.
-    let output_dir = opts.output_dir.unwrap_or(source_dir);
+    let config = Config::load(opts.config.as_deref())?;
+    let artist_join = opts.artist_join.or(config.artist_join).unwrap_or_default();
+    let sanitizer = Sanitizer::new(
+        opts.sanitize.sanitize,
+        opts.sanitize.replacement,
+        opts.sanitize.replace_char,
+        opts.sanitize.max_name_bytes,
+    );
 
     // Load the incoming metadata (the metadata the user has configured to be
     // written to the tags).
-    let incoming_metadata = reader::load_split_metadata(&album_block_file, &track_blocks_file);
+    let incoming_metadata = reader::load_split_metadata(
+        &album_block_file,
+        disc_blocks_file.as_deref(),
+        &track_blocks_file,
+    )?;
 
-    // Write out the incoming metadata to the output directory.
-    writer::write_output_metadata_file(&output_dir, &incoming_metadata);
+    // If no output directory is given, use the source directory. With an
+    // output directory template, the output directory is the library that
+    // the album directory goes in.
+    let output_dir = match opts.output_dir_template.or(config.output_dir_template) {
+        Some(template) => plan::plan_output_dir(
+            &template,
+            &incoming_metadata.album,
+            &sanitizer,
+            artist_join,
+            &opts.output_dir.ok_or(Error::MissingOutputDir)?,
+            &source_dir,
+            opts.merge,
+        )?,
+        None => opts.output_dir.unwrap_or_else(|| source_dir.clone()),
+    };
+
+    // Load the cover image up front, so that a bad image fails early.
+    let cover_file = match opts.cover_file {
+        Some(cover_file) => Some(cover_file),
+        None if opts.cover => {
+            Some(
+                picture::find_cover(&source_dir)?.ok_or_else(|| Error::MissingCover {
+                    dir: source_dir.clone(),
+                })?,
+            )
+        }
+        None => None,
+    };
+    let picture_options = PictureOptions {
+        keep_existing: opts.keep_pictures,
+        cover: cover_file.as_deref().map(picture::load_cover).transpose()?,
+    };
+
+    // Work out what will happen to each track before touching any files.
+    let preserve_policy = PreservePolicy::new(opts.keep_tags, opts.drop_tags);
+    let file_name_template = opts.file_name_template.or(config.file_name_template);
+    let mut plans = plan::plan_tracks(
+        tracks,
+        &incoming_metadata,
+        &preserve_policy,
+        file_name_template.as_ref(),
+        &sanitizer,
+        artist_join,
+        &output_dir,
+    )?;
+    plan::resolve_collisions(&mut plans, opts.on_collision, &sanitizer, opts.copy)?;
+
+    let gain_tags = opts.gain_tags;
+    let gain_options = GainOptions {
+        format: gain_tags,
+        reference: opts
+            .reference
+            .unwrap_or_else(|| gain_tags.default_reference()),
+        write_range: opts.write_range,
+    };
+
+    if let Some(diff_format) = opts.diff {
+        let diffs = diff::diff_plans(&plans, &gain_options);
+
+        match diff_format {
+            DiffFormat::Text => diff::print_diff(&diffs),
+            DiffFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&diffs).expect("diffs are always serializable")
+            ),
+        }
+
+        // Give the user a chance to abort before anything is changed.
+        if !opts.dry_run {
+            helpers::pause().map_err(Error::io(Path::new("<stdin>")))?;
+        }
+    }
+
+    if opts.dry_run {
+        // The diff already shows the plan, and must be the only output.
+        if opts.diff.is_none() {
+            plan::print_plan(&plans);
+        }
+        eprintln!(
+            "Dry run: no files were modified, {} would be written",
+            output_dir.join("meta.json").display(),
+        );
+        return Ok(());
+    }
+
+    // Create the output directory if needed, and remove it again if the
+    // tracks could not be processed.
+    let created_dirs = transaction::create_dirs(&output_dir)?;
+
+    let result = process_tracks(
+        plans,
+        &incoming_metadata,
+        &output_dir,
+        opts.copy,
+        &picture_options,
+        &gain_options,
+        &opts.analysis,
+    );
+
+    if result.is_err() {
+        transaction::remove_created_dirs(created_dirs);
+    }
+
+    result
+}
+
+fn main() {
+    let opts = Opts::parse();
 
-    process_tracks(tracks, incoming_metadata, &output_dir);
+    let result = match opts.command {
+        Some(Command::Analyze(analyze_opts)) => analyze(analyze_opts),
+        Some(Command::Check(check_opts)) => check(check_opts),
+        None => retag(opts.retag),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(err.exit_code());
+    }
 }
@@ -48,11 +48,16 @@ pub type MetaBlock = BTreeMap<String, MetaVal>;
 pub type MetaBlockList = Vec<MetaBlock>;
 
 /// The combined representation of an album's metadata. This includes metadata
-/// about the album itself, as well as its contained tracks.
+/// about the album itself, optionally about each of its discs, and about its
+/// contained tracks. Each track inherits the keys of its disc block, which in
+/// turn inherits the keys of the album block.
 #[derive(Debug, Deserialize, Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub(crate) struct Metadata {
     pub album: MetaBlock,
+    /// One block per disc, in disc order. May be empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discs: MetaBlockList,
     pub tracks: MetaBlockList,
 }
 
@@ -65,7 +70,6 @@ mod tests {
 
     use big_s::S;
     use maplit::btreemap;
-    use serde_json;
 
     #[test]
     fn test_metadata__deserialize() {
@@ -105,7 +109,7 @@ mod tests {
             }
         "#;
 
-        let deserialized: Metadata = serde_json::from_str(&serialized).unwrap();
+        let deserialized: Metadata = serde_json::from_str(serialized).unwrap();
 
         assert_eq!(
             deserialized,
@@ -116,6 +120,7 @@ mod tests {
                     S("date") => One(S("2023-05-30")),
                     S("vendor") => One(S("Qobuz")),
                 },
+                discs: vec![],
                 tracks: vec![
                     btreemap! {
                         S("artist") => One(S("Dani J")),
@@ -151,6 +156,7 @@ mod tests {
                 S("date") => One(S("2023-05-30")),
                 S("vendor") => One(S("Qobuz")),
             },
+            discs: vec![],
             tracks: vec![
                 btreemap! {
                     S("artist") => One(S("Dani J")),
@@ -182,6 +188,57 @@ mod tests {
         assert_eq!(deserialized, metadata);
     }
 
+    #[test]
+    fn test_metadata__discs() {
+        let serialized: &'static str = r#"
+            {
+                "album": {
+                    "album": "Box Set"
+                },
+                "discs": [
+                    {
+                        "discsubtitle": "Live"
+                    },
+                    {
+                        "discsubtitle": "Studio",
+                        "date": "2001"
+                    }
+                ],
+                "tracks": [
+                    {
+                        "title": "A"
+                    },
+                    {
+                        "title": "B"
+                    }
+                ]
+            }
+        "#;
+
+        let deserialized: Metadata = serde_json::from_str(serialized).unwrap();
+
+        assert_eq!(
+            deserialized.discs,
+            vec![
+                btreemap! {
+                    S("discsubtitle") => One(S("Live")),
+                },
+                btreemap! {
+                    S("date") => One(S("2001")),
+                    S("discsubtitle") => One(S("Studio")),
+                },
+            ]
+        );
+
+        // Metadata without discs keeps the two-level format.
+        let metadata = Metadata {
+            discs: vec![],
+            ..deserialized
+        };
+        let serialized = serde_json::to_value(&metadata).unwrap();
+        assert!(serialized.get("discs").is_none());
+    }
+
     #[test]
     fn test_meta_val__display() {
         let meta_val = MetaVal::One(S("VALUE"));
@@ -1,19 +1,200 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use glob::Pattern;
 
+use crate::diff::DiffFormat;
+use crate::display::ArtistJoin;
+use crate::loudness::ReferenceLevel;
+use crate::plan::CollisionPolicy;
+use crate::reader::TrackOrder;
+use crate::sanitize::{CharReplacement, SanitizePolicy};
+use crate::template::Template;
+use crate::writer::GainTagFormat;
+
+/// Tags FLAC albums from JSON metadata files, and writes loudness
+/// normalization tags measured from their audio.
 #[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub(crate) struct Opts {
-    pub(crate) source_dir: PathBuf,
+    #[clap(subcommand)]
+    pub(crate) command: Option<Command>,
+    #[clap(flatten)]
+    pub(crate) retag: RetagOpts,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Measure the loudness of the tracks in a directory, without modifying
+    /// any files.
+    Analyze(AnalyzeOpts),
+    /// Verify the existing ReplayGain tags of the tracks in a directory
+    /// against a fresh loudness measurement.
+    Check(CheckOpts),
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct RetagOpts {
+    #[clap(required = true)]
+    pub(crate) source_dir: Option<PathBuf>,
     #[clap(long)]
     pub(crate) album_block_file: Option<PathBuf>,
     #[clap(long)]
     pub(crate) track_blocks_file: Option<PathBuf>,
+    /// File with one metadata block per disc, whose keys are inherited by
+    /// the tracks of that disc. Defaults to `disc.json` in the source
+    /// directory, if it exists.
+    #[clap(long)]
+    pub(crate) disc_blocks_file: Option<PathBuf>,
+    /// Which source of track and disc numbers wins when they disagree.
+    /// Tracks without a number in either are ordered by file name.
+    #[clap(long, value_enum, default_value_t = TrackOrder::Tags)]
+    pub(crate) track_order: TrackOrder,
     #[clap(long)]
     pub(crate) emit_existing: bool,
     #[clap(long)]
     pub(crate) emit_existing_to: Option<PathBuf>,
+    /// Directory to write the tagged tracks to. Defaults to the source
+    /// directory. With an output directory template, this is the library
+    /// directory that the album directory is created in.
     #[clap(long)]
     pub(crate) output_dir: Option<PathBuf>,
+    /// Template for the album directory inside of the output directory, such
+    /// as `{albumartist}/{date:4} - {album}`, filled in from the album block.
+    /// Missing directories are created.
+    #[clap(long)]
+    pub(crate) output_dir_template: Option<Template>,
+    /// Write into an album directory from the output directory template even
+    /// if it already exists and is not empty.
+    #[clap(long)]
+    pub(crate) merge: bool,
+    /// What to do when tracks would be written to the same file, or over an
+    /// existing file. Collisions are checked for before any file is touched.
+    #[clap(long, value_enum, default_value_t = CollisionPolicy::Abort)]
+    pub(crate) on_collision: CollisionPolicy,
+    /// Template for the names of output files, without the extension, such
+    /// as `[{discnumber}-]{tracknumber:02} {artist} - {title}`. `{key}`
+    /// inserts the value of any merged tag, `{key:02}` pads it with zeros,
+    /// `{key:4}` keeps its first 4 characters, and a `[...]` segment is left
+    /// out if a key in it is missing.
+    #[clap(long)]
+    pub(crate) file_name_template: Option<Template>,
+    /// How multiple artists are joined in file and directory names. An
+    /// explicit `displayartist` or `artistsort` key is used instead, if
+    /// present. Defaults to `comma`.
+    #[clap(long, value_enum)]
+    pub(crate) artist_join: Option<ArtistJoin>,
+    /// JSON config file with defaults for `file_name_template`,
+    /// `output_dir_template` and `artist_join`. Defaults to
+    /// `marktag/config.json` in the user config directory, if it exists.
+    #[clap(long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Leave the source files untouched, and write tagged copies to the
+    /// output directory instead of moving them there.
+    #[clap(long, requires = "output_dir")]
+    pub(crate) copy: bool,
+    /// Keep the pictures embedded in the source files, instead of removing
+    /// them. An embedded cover replaces only the existing front covers.
+    #[clap(long)]
+    pub(crate) keep_pictures: bool,
+    /// Embed `cover.jpg`, `cover.png`, `folder.jpg` or `folder.png` from the
+    /// source directory as the front cover.
+    #[clap(long)]
+    pub(crate) cover: bool,
+    /// Embed the given PNG or JPEG image as the front cover.
+    #[clap(long, conflicts_with = "cover")]
+    pub(crate) cover_file: Option<PathBuf>,
+    /// Which loudness normalization tags to write.
+    #[clap(long, value_enum, default_value_t = GainTagFormat::ReplayGain)]
+    pub(crate) gain_tags: GainTagFormat,
+    /// Reference loudness to calculate gains against: `rg2` (-18 LUFS),
+    /// `r128` (-23 LUFS), or a custom loudness in LUFS. Defaults to the
+    /// conventional reference for the chosen gain tags.
+    #[clap(long, allow_hyphen_values = true)]
+    pub(crate) reference: Option<ReferenceLevel>,
+    /// Also write the loudness range (LRA) of each track and the album.
+    #[clap(long)]
+    pub(crate) write_range: bool,
+    /// Print the tags, file name and destination of each track, without
+    /// modifying any files.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+    /// Show how the tags of each track will change, as colored text or as
+    /// JSON, and wait for confirmation before continuing.
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "text")]
+    pub(crate) diff: Option<DiffFormat>,
+    /// Glob patterns of existing tag keys to keep, such as `isrc` or
+    /// `musicbrainz_*`. By default, all existing tags are replaced.
+    #[clap(long, value_delimiter = ',')]
+    pub(crate) keep_tags: Vec<Pattern>,
+    /// Glob patterns of existing tag keys to remove, even if they match a
+    /// keep pattern. If only these are given, all other tags are kept.
+    #[clap(long, value_delimiter = ',')]
+    pub(crate) drop_tags: Vec<Pattern>,
+    #[clap(flatten)]
+    pub(crate) sanitize: SanitizeOpts,
+    #[clap(flatten)]
+    pub(crate) analysis: AnalysisOpts,
+}
+
+/// Options controlling how output file and directory names are sanitized.
+#[derive(Debug, Args)]
+pub(crate) struct SanitizeOpts {
+    /// Which file systems output names must be valid on.
+    #[clap(long, value_enum, default_value_t = SanitizePolicy::Posix)]
+    pub(crate) sanitize: SanitizePolicy,
+    /// Replacement for forbidden characters. By default, they are removed.
+    #[clap(long, default_value = "")]
+    pub(crate) replacement: String,
+    /// Replacement for a specific forbidden character, as `CHAR=REPLACEMENT`
+    /// (e.g. `:=-`). May be given multiple times.
+    #[clap(long)]
+    pub(crate) replace_char: Vec<CharReplacement>,
+    /// Maximum length of each output file and directory name, in bytes.
+    /// File names are shortened without cutting off their extension.
+    #[clap(long, default_value_t = 255)]
+    pub(crate) max_name_bytes: usize,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct AnalyzeOpts {
+    pub(crate) source_dir: PathBuf,
+    /// Print the report as JSON instead of a table.
+    #[clap(long)]
+    pub(crate) json: bool,
+    /// Reference loudness to calculate gains against: `rg2` (-18 LUFS),
+    /// `r128` (-23 LUFS), or a custom loudness in LUFS.
+    #[clap(long, allow_hyphen_values = true, default_value = "rg2")]
+    pub(crate) reference: ReferenceLevel,
+    #[clap(flatten)]
+    pub(crate) analysis: AnalysisOpts,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct CheckOpts {
+    pub(crate) source_dir: PathBuf,
+    /// Maximum allowed deviation of stored gains, in dB.
+    #[clap(long, default_value_t = 0.1)]
+    pub(crate) tolerance: f32,
+    /// Maximum allowed deviation of stored peaks, in dB.
+    #[clap(long, default_value_t = 0.2)]
+    pub(crate) peak_tolerance: f32,
+    #[clap(flatten)]
+    pub(crate) analysis: AnalysisOpts,
+}
+
+/// Options controlling how loudness analysis is run.
+#[derive(Debug, Args)]
+pub(crate) struct AnalysisOpts {
+    /// Number of tracks to analyze concurrently (0 uses one per CPU).
+    #[clap(long, short, default_value_t = 0)]
+    pub(crate) jobs: usize,
+    /// Path of the loudness cache file. Defaults to `marktag/loudness.json`
+    /// in the user cache directory.
+    #[clap(long)]
+    pub(crate) loudness_cache: Option<PathBuf>,
+    /// Always decode every track, without using the loudness cache.
+    #[clap(long, conflicts_with = "loudness_cache")]
+    pub(crate) no_loudness_cache: bool,
 }
@@ -0,0 +1,308 @@
+use std::path::{Path, PathBuf};
+
+use metaflac::block::{Picture, PictureType};
+
+use crate::error::{Error, Result};
+
+/// File names that are searched for a cover image, in order of preference.
+const COVER_FILE_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "folder.jpeg",
+    "folder.png",
+];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The properties of an image that are stored in a FLAC PICTURE block.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ImageInfo {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel.
+    pub depth: u32,
+    /// Number of colors in the palette, or 0 if the image is not indexed.
+    pub num_colors: u32,
+}
+
+fn read_u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the image header (IHDR) of a PNG file, and the palette size (PLTE)
+/// of indexed images.
+fn parse_png(data: &[u8]) -> Option<ImageInfo> {
+    let mut offset = PNG_SIGNATURE.len();
+
+    if !data.starts_with(PNG_SIGNATURE) || data.get(offset + 4..offset + 8)? != b"IHDR" {
+        return None;
+    }
+
+    let width = read_u32_be(data, offset + 8)?;
+    let height = read_u32_be(data, offset + 12)?;
+    let bit_depth = *data.get(offset + 16)? as u32;
+    let color_type = *data.get(offset + 17)?;
+
+    let depth = match color_type {
+        // Greyscale.
+        0 => bit_depth,
+        // Truecolor.
+        2 => bit_depth * 3,
+        // Indexed, where palette entries are always 8-bit RGB.
+        3 => 24,
+        // Greyscale with alpha.
+        4 => bit_depth * 2,
+        // Truecolor with alpha.
+        6 => bit_depth * 4,
+        _ => return None,
+    };
+
+    let mut num_colors = 0;
+
+    if color_type == 3 {
+        // Each chunk is a length, a type, its data, and a CRC.
+        loop {
+            let length = read_u32_be(data, offset)? as usize;
+            let chunk_type = data.get(offset + 4..offset + 8)?;
+
+            if chunk_type == b"PLTE" {
+                num_colors = (length / 3) as u32;
+                break;
+            }
+            if chunk_type == b"IDAT" {
+                return None;
+            }
+
+            offset = offset.checked_add(length)?.checked_add(12)?;
+        }
+    }
+
+    Some(ImageInfo {
+        mime_type: "image/png",
+        width,
+        height,
+        depth,
+        num_colors,
+    })
+}
+
+/// Reads the frame header (SOFn) of a JPEG file.
+fn parse_jpeg(data: &[u8]) -> Option<ImageInfo> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut offset = 2;
+
+    loop {
+        if *data.get(offset)? != 0xff {
+            return None;
+        }
+
+        // Markers may be preceded by any number of fill bytes.
+        let mut marker = *data.get(offset + 1)?;
+        while marker == 0xff {
+            offset += 1;
+            marker = *data.get(offset + 1)?;
+        }
+        offset += 2;
+
+        match marker {
+            // Standalone markers, without a segment.
+            0x01 | 0xd0..=0xd7 => continue,
+            // SOFn, except for DHT, JPG and DAC, which share the range.
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                let precision = *data.get(offset + 2)? as u32;
+                let height = read_u16_be(data, offset + 3)? as u32;
+                let width = read_u16_be(data, offset + 5)? as u32;
+                let components = *data.get(offset + 7)? as u32;
+
+                return Some(ImageInfo {
+                    mime_type: "image/jpeg",
+                    width,
+                    height,
+                    depth: precision * components,
+                    num_colors: 0,
+                });
+            }
+            // Start of scan or end of image, before any frame header.
+            0xda | 0xd9 => return None,
+            _ => {
+                let length = read_u16_be(data, offset)? as usize;
+                offset += length;
+            }
+        }
+    }
+}
+
+/// Reads the properties of a PNG or JPEG image, identified by its contents.
+pub(crate) fn read_image_info(data: &[u8]) -> std::result::Result<ImageInfo, String> {
+    if data.starts_with(PNG_SIGNATURE) {
+        parse_png(data).ok_or_else(|| String::from("invalid PNG header"))
+    } else if data.starts_with(&[0xff, 0xd8]) {
+        parse_jpeg(data).ok_or_else(|| String::from("invalid JPEG header"))
+    } else {
+        Err(String::from("not a PNG or JPEG image"))
+    }
+}
+
+/// Looks for a cover image in a directory, matching file names regardless
+/// of case.
+pub(crate) fn find_cover(dir: &Path) -> Result<Option<PathBuf>> {
+    let mut candidates = Vec::new();
+
+    for entry in dir.read_dir().map_err(Error::io(dir))? {
+        let path = entry.map_err(Error::io(dir))?.path();
+        let file_name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+
+        if let Some(rank) = COVER_FILE_NAMES.iter().position(|n| *n == file_name) {
+            candidates.push((rank, path));
+        }
+    }
+
+    candidates.sort();
+
+    Ok(candidates.into_iter().next().map(|(_, path)| path))
+}
+
+/// Loads an image file as a front cover PICTURE block.
+pub(crate) fn load_cover(path: &Path) -> Result<Picture> {
+    eprintln!("Loading cover image: {}", path.display());
+
+    let data = std::fs::read(path).map_err(Error::io(path))?;
+    let info = read_image_info(&data).map_err(|reason| Error::Image {
+        path: path.to_path_buf(),
+        reason,
+    })?;
+
+    let mut picture = Picture::new();
+    picture.picture_type = PictureType::CoverFront;
+    picture.mime_type = String::from(info.mime_type);
+    picture.width = info.width;
+    picture.height = info.height;
+    picture.depth = info.depth;
+    picture.num_colors = info.num_colors;
+    picture.data = data;
+
+    Ok(picture)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn png_chunk(chunk_type: &[u8], chunk_data: &[u8]) -> Vec<u8> {
+        let mut chunk = (chunk_data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(chunk_data);
+        // The CRC is not checked.
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png(width: u32, height: u32, bit_depth: u8, color_type: u8, palette: usize) -> Vec<u8> {
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(png_chunk(b"IHDR", &ihdr));
+        data.extend(png_chunk(b"tEXt", b"Comment\0cover"));
+        if palette > 0 {
+            data.extend(png_chunk(b"PLTE", &vec![0; palette * 3]));
+        }
+        data.extend(png_chunk(b"IDAT", &[]));
+        data
+    }
+
+    fn jpeg(width: u16, height: u16, components: u8) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8];
+        // APP0 segment, followed by fill bytes.
+        data.extend_from_slice(&[0xff, 0xe0, 0x00, 0x04, 0x4a, 0x46, 0xff]);
+        // SOF2 segment.
+        data.extend_from_slice(&[0xff, 0xc2, 0x00, 0x0b, 8]);
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&[components, 1, 0x11, 0]);
+        data.extend_from_slice(&[0xff, 0xd9]);
+        data
+    }
+
+    #[test]
+    fn test_read_image_info__png() {
+        assert_eq!(
+            read_image_info(&png(600, 500, 8, 2, 0)),
+            Ok(ImageInfo {
+                mime_type: "image/png",
+                width: 600,
+                height: 500,
+                depth: 24,
+                num_colors: 0,
+            })
+        );
+
+        let info = read_image_info(&png(1, 1, 16, 6, 0)).unwrap();
+        assert_eq!((info.depth, info.num_colors), (64, 0));
+
+        let info = read_image_info(&png(1, 1, 4, 3, 16)).unwrap();
+        assert_eq!((info.depth, info.num_colors), (24, 16));
+
+        // Indexed images must have a palette.
+        assert!(read_image_info(&png(1, 1, 8, 3, 0)).is_err());
+    }
+
+    #[test]
+    fn test_read_image_info__jpeg() {
+        assert_eq!(
+            read_image_info(&jpeg(1200, 1000, 3)),
+            Ok(ImageInfo {
+                mime_type: "image/jpeg",
+                width: 1200,
+                height: 1000,
+                depth: 24,
+                num_colors: 0,
+            })
+        );
+
+        let info = read_image_info(&jpeg(10, 10, 1)).unwrap();
+        assert_eq!(info.depth, 8);
+    }
+
+    #[test]
+    fn test_read_image_info__invalid() {
+        assert!(read_image_info(b"GIF89a").is_err());
+        assert!(read_image_info(&png(1, 1, 8, 2, 0)[..20]).is_err());
+        assert!(read_image_info(&jpeg(1, 1, 3)[..12]).is_err());
+        assert!(read_image_info(&[]).is_err());
+    }
+
+    #[test]
+    fn test_find_cover() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        assert_eq!(find_cover(dir).unwrap(), None);
+
+        std::fs::write(dir.join("Folder.PNG"), "").unwrap();
+        assert_eq!(find_cover(dir).unwrap(), Some(dir.join("Folder.PNG")));
+
+        std::fs::write(dir.join("cover.png"), "").unwrap();
+        std::fs::write(dir.join("back.jpg"), "").unwrap();
+        assert_eq!(find_cover(dir).unwrap(), Some(dir.join("cover.png")));
+    }
+}
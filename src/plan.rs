@@ -0,0 +1,720 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::display::{self, ArtistJoin};
+use crate::error::{Error, Result};
+use crate::helpers::{self, Track};
+use crate::metadata::{MetaBlock, MetaVal, Metadata};
+use crate::preserve::PreservePolicy;
+use crate::sanitize::Sanitizer;
+use crate::template::Template;
+
+/// Everything that will be done to a single track: the tags it will end up
+/// with, and where it will be written.
+pub(crate) struct TrackPlan {
+    pub track: Track,
+    pub tags: MetaBlock,
+    pub output_file_name: String,
+    pub output_path: PathBuf,
+}
+
+/// What to do when output files would overwrite each other, or files that
+/// already exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CollisionPolicy {
+    /// Stop with a report of every collision, before any file is modified.
+    #[default]
+    Abort,
+    /// Add a number to the names of later tracks, such as `01. Intro (2).flac`.
+    Number,
+    /// Replace existing files. Tracks with the same output name still abort.
+    Overwrite,
+}
+
+/// An output file that would overwrite another output file, or an existing
+/// file.
+#[derive(Debug)]
+pub(crate) struct Collision {
+    pub dest: PathBuf,
+    /// The position of the track that collides.
+    pub track: String,
+    /// The position of the earlier track with the same output file, if the
+    /// collision is not with an existing file.
+    pub other_track: Option<String>,
+}
+
+impl Display for Collision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.other_track {
+            Some(other_track) => write!(
+                f,
+                "{}: tracks {} and {} would be written to the same file",
+                self.dest.display(),
+                other_track,
+                self.track
+            ),
+            None => write!(
+                f,
+                "{}: track {} would overwrite an existing file",
+                self.dest.display(),
+                self.track
+            ),
+        }
+    }
+}
+
+/// The position of a track within its album, as written to its tags.
+struct Position {
+    track: usize,
+    /// The number of tracks on the disc of the track.
+    total_tracks: usize,
+    /// The disc of the track and the number of discs, on multi-disc releases.
+    disc: Option<(usize, usize)>,
+}
+
+impl Position {
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        let mut tags = vec![
+            ("tracknumber", self.track.to_string()),
+            ("totaltracks", self.total_tracks.to_string()),
+        ];
+
+        if let Some((disc, total_discs)) = self.disc {
+            tags.push(("discnumber", disc.to_string()));
+            tags.push(("disctotal", total_discs.to_string()));
+        }
+
+        tags
+    }
+}
+
+/// Layers the album, disc and track blocks on top of the preserved existing
+/// tags, into the full set of tags for a track. Vorbis comment field names
+/// are case-insensitive, so keys are lowercased. Track fields take
+/// precedence over disc fields, then album fields, then preserved fields.
+fn merge_tags(
+    preserved: MetaBlock,
+    album_block: &MetaBlock,
+    disc_block: Option<&MetaBlock>,
+    track_block: &MetaBlock,
+    position: &Position,
+) -> MetaBlock {
+    let mut tags = preserved;
+
+    for (k, v) in album_block
+        .iter()
+        .chain(disc_block.into_iter().flatten())
+        .chain(track_block)
+    {
+        tags.insert(k.to_ascii_lowercase(), v.clone());
+    }
+
+    for (k, v) in position.tags() {
+        tags.insert(String::from(k), MetaVal::One(v));
+    }
+
+    tags
+}
+
+/// Pairs each track with its incoming track block, and works out the tags
+/// and output location of each track, without touching any files. Output
+/// file names follow the template if one is given, or else the default
+/// `{track}. {artist} - {title}` scheme, and are then sanitized. Multiple
+/// artists are joined in the given style.
+pub(crate) fn plan_tracks(
+    tracks: Vec<Track>,
+    incoming_metadata: &Metadata,
+    preserve_policy: &PreservePolicy,
+    file_name_template: Option<&Template>,
+    sanitizer: &Sanitizer,
+    artist_join: ArtistJoin,
+    output_dir: &Path,
+) -> Result<Vec<TrackPlan>> {
+    let Metadata {
+        album: incoming_album_block,
+        discs: incoming_disc_blocks,
+        tracks: incoming_track_blocks,
+    } = incoming_metadata;
+
+    // Ensure equal numbers of tracks and track blocks.
+    if tracks.len() != incoming_track_blocks.len() {
+        return Err(Error::TrackCount {
+            tracks: tracks.len(),
+            track_blocks: incoming_track_blocks.len(),
+        });
+    }
+
+    // Count the tracks on each disc. Track numbers are padded to the same
+    // width on every disc.
+    let mut disc_totals = BTreeMap::<Option<usize>, usize>::new();
+    for track in tracks.iter() {
+        *disc_totals.entry(track.disc).or_default() += 1;
+    }
+    // A lone disc that is not disc 1 is part of a larger release, so the disc
+    // total is the highest disc number rather than the number of discs.
+    let total_discs = disc_totals.keys().flatten().max().copied().unwrap_or(1);
+
+    // Disc blocks are optional, but if given, each disc needs one.
+    if !incoming_disc_blocks.is_empty() && incoming_disc_blocks.len() != disc_totals.len() {
+        return Err(Error::DiscCount {
+            discs: disc_totals.len(),
+            disc_blocks: incoming_disc_blocks.len(),
+        });
+    }
+
+    let num_digits = format!("{}", disc_totals.values().max().unwrap_or(&0)).len();
+
+    let mut plans = Vec::with_capacity(tracks.len());
+
+    for (track, incoming_track_block) in tracks.into_iter().zip(incoming_track_blocks) {
+        let position = Position {
+            track: track.index,
+            total_tracks: disc_totals[&track.disc],
+            disc: track.disc.map(|disc| (disc, total_discs)),
+        };
+        // The disc blocks go with the discs in order, which also matches a
+        // lone disc that is not disc 1 with the only disc block.
+        let disc_block = disc_totals
+            .keys()
+            .position(|&disc| disc == track.disc)
+            .and_then(|i| incoming_disc_blocks.get(i));
+        let tags = merge_tags(
+            preserve_policy.preserved_comments(&track.tag),
+            incoming_album_block,
+            disc_block,
+            incoming_track_block,
+            &position,
+        );
+
+        let missing_key = |key| Error::MissingKey {
+            track: track.position(),
+            key,
+        };
+
+        let display_tags = display::display_tags(&tags, artist_join);
+
+        let ext = track.path.extension().unwrap_or_default().to_string_lossy();
+        let output_file_stem = match file_name_template {
+            Some(template) => template
+                .render(&display_tags, &sanitizer.separator_replacement())
+                .map_err(missing_key)?,
+            None => {
+                let get_key = |key: &str| {
+                    display_tags
+                        .get(key)
+                        .map(|val| val.to_string())
+                        .ok_or_else(|| missing_key(String::from(key)))
+                };
+                let display_artist = get_key("artist")?;
+                let display_title = get_key("title")?;
+
+                helpers::generate_output_file_name(
+                    track.disc,
+                    track.index,
+                    num_digits,
+                    &display_artist,
+                    &display_title,
+                )
+            }
+        };
+        let output_file_name = sanitizer.sanitize_file_name(&output_file_stem, &ext);
+        let output_path = output_dir.join(&output_file_name);
+
+        plans.push(TrackPlan {
+            track,
+            tags,
+            output_file_name,
+            output_path,
+        });
+    }
+
+    Ok(plans)
+}
+
+/// Identifies the file a path refers to for collision checks. Names are
+/// compared regardless of case, since the output may end up on a file system
+/// that does.
+fn collision_key(path: &Path) -> String {
+    let path = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => parent
+            .canonicalize()
+            .unwrap_or_else(|_| parent.to_path_buf())
+            .join(file_name),
+        _ => path.to_path_buf(),
+    };
+
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Lists the collision keys of the entries of a directory, so that existing
+/// files are matched the same way as planned ones. A directory that cannot be
+/// read has no entries.
+fn existing_keys(dir: &Path) -> HashSet<String> {
+    dir.read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| collision_key(&entry.path()))
+        .collect()
+}
+
+/// Checks the planned output files of the whole album for collisions, with
+/// each other and with existing files, before any file is touched. Source
+/// files that are moved away are not collisions, unless `keep_sources` is
+/// set. Depending on the policy, collisions are an error, or are resolved by
+/// renaming the later tracks.
+pub(crate) fn resolve_collisions(
+    plans: &mut [TrackPlan],
+    policy: CollisionPolicy,
+    sanitizer: &Sanitizer,
+    keep_sources: bool,
+) -> Result<()> {
+    let moved_sources = if keep_sources {
+        HashSet::new()
+    } else {
+        plans
+            .iter()
+            .map(|plan| collision_key(&plan.track.path))
+            .collect()
+    };
+
+    // The collision keys of existing files, listed once per output directory.
+    let mut existing_files = HashMap::<PathBuf, HashSet<String>>::new();
+
+    // The output files claimed so far, with the tracks that claimed them.
+    let mut claimed = HashMap::<String, String>::new();
+    let mut collisions = Vec::new();
+
+    for plan in plans.iter_mut() {
+        let file_name = plan.output_file_name.clone();
+        let mut num = 1;
+
+        loop {
+            let key = collision_key(&plan.output_path);
+            let other_track = claimed.get(&key).cloned();
+
+            let dir = plan.output_path.parent().unwrap_or_else(|| Path::new(""));
+            let is_existing_file = policy != CollisionPolicy::Overwrite
+                && !moved_sources.contains(&key)
+                && existing_files
+                    .entry(dir.to_path_buf())
+                    .or_insert_with_key(|dir| existing_keys(dir))
+                    .contains(&key);
+
+            if other_track.is_none() && !is_existing_file {
+                claimed.insert(key, plan.track.position());
+                break;
+            }
+
+            if policy == CollisionPolicy::Number {
+                num += 1;
+                plan.output_file_name = sanitizer.add_suffix(&file_name, &format!(" ({})", num));
+                plan.output_path.set_file_name(&plan.output_file_name);
+                continue;
+            }
+
+            collisions.push(Collision {
+                dest: plan.output_path.clone(),
+                track: plan.track.position(),
+                other_track,
+            });
+            break;
+        }
+    }
+
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Collisions { collisions })
+    }
+}
+
+/// Whether a directory is empty. A directory that does not exist yet counts
+/// as empty.
+fn is_empty_dir(dir: &Path) -> Result<bool> {
+    match dir.read_dir() {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(Error::io(dir)(err)),
+    }
+}
+
+/// Works out the album directory inside of the library directory, from the
+/// output directory template filled in with the album block, with multiple
+/// artists joined in the given style. Each directory name is sanitized.
+/// Unless merging is allowed, the album directory must be empty or the source
+/// directory.
+pub(crate) fn plan_output_dir(
+    template: &Template,
+    album_block: &MetaBlock,
+    sanitizer: &Sanitizer,
+    artist_join: ArtistJoin,
+    library_dir: &Path,
+    source_dir: &Path,
+    allow_merge: bool,
+) -> Result<PathBuf> {
+    // Template keys are lowercase, as in the merged tags of a track.
+    let album_block = album_block
+        .iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v.clone()))
+        .collect::<MetaBlock>();
+    let album_block = display::display_tags(&album_block, artist_join);
+
+    let album_path = template
+        .render(&album_block, &sanitizer.separator_replacement())
+        .map(PathBuf::from)
+        .map_err(|key| Error::MissingAlbumKey { key })?;
+
+    // Keep the album directory inside of the library directory.
+    let is_relative = album_path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if album_path.as_os_str().is_empty() || !is_relative {
+        return Err(Error::OutputDir {
+            dir: album_path,
+            reason: String::from("not a relative path of directory names"),
+        });
+    }
+
+    let album_dir = album_path
+        .components()
+        .map(|c| sanitizer.sanitize_dir_name(&c.as_os_str().to_string_lossy()))
+        .fold(library_dir.to_path_buf(), |dir, name| dir.join(name));
+
+    let is_source_dir = match (album_dir.canonicalize(), source_dir.canonicalize()) {
+        (Ok(album_dir), Ok(source_dir)) => album_dir == source_dir,
+        _ => false,
+    };
+
+    if !allow_merge && !is_source_dir && !is_empty_dir(&album_dir)? {
+        return Err(Error::OutputDir {
+            dir: album_dir,
+            reason: String::from("already exists and is not empty (use --merge to write into it)"),
+        });
+    }
+
+    Ok(album_dir)
+}
+
+/// Prints the plan for each track, in the `key=value` form of vorbis comments.
+pub(crate) fn print_plan(plans: &[TrackPlan]) {
+    for plan in plans {
+        println!(
+            "Track {}: {}",
+            plan.track.position(),
+            plan.track.path.display()
+        );
+        println!("  -> {}", plan.output_path.display());
+
+        for (key, val) in plan.tags.iter() {
+            for v in val.clone().into_vec() {
+                println!("  {}={}", key, v);
+            }
+        }
+
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use big_s::S;
+    use maplit::btreemap;
+    use metaflac::Tag;
+
+    use crate::sanitize::SanitizePolicy;
+
+    #[test]
+    fn test_merge_tags() {
+        let preserved = btreemap! {
+            S("album") => MetaVal::One(S("Old Album")),
+            S("isrc") => MetaVal::One(S("USRC17607839")),
+        };
+        let album_block = btreemap! {
+            S("album") => MetaVal::One(S("Album")),
+            S("Artist") => MetaVal::One(S("Album Artist")),
+            S("tracknumber") => MetaVal::One(S("99")),
+        };
+        let track_block = btreemap! {
+            S("artist") => MetaVal::Many(vec![S("A"), S("B")]),
+            S("title") => MetaVal::One(S("Title")),
+        };
+
+        let position = Position {
+            track: 3,
+            total_tracks: 12,
+            disc: None,
+        };
+
+        let expected = btreemap! {
+            S("album") => MetaVal::One(S("Album")),
+            S("artist") => MetaVal::Many(vec![S("A"), S("B")]),
+            S("isrc") => MetaVal::One(S("USRC17607839")),
+            S("title") => MetaVal::One(S("Title")),
+            S("totaltracks") => MetaVal::One(S("12")),
+            S("tracknumber") => MetaVal::One(S("3")),
+        };
+
+        assert_eq!(
+            merge_tags(preserved, &album_block, None, &track_block, &position),
+            expected
+        );
+    }
+
+    fn track_plan(dir: &Path, index: usize, output_file_name: &str) -> TrackPlan {
+        TrackPlan {
+            track: Track {
+                index,
+                disc: None,
+                path: dir.join(format!("track{}.flac", index)),
+                tag: metaflac::Tag::new(),
+            },
+            tags: MetaBlock::new(),
+            output_file_name: S(output_file_name),
+            output_path: dir.join(output_file_name),
+        }
+    }
+
+    #[test]
+    fn test_resolve_collisions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let sanitizer = Sanitizer::default();
+
+        std::fs::write(dir.join("track1.flac"), "").unwrap();
+        std::fs::write(dir.join("04. D.flac"), "").unwrap();
+        std::fs::write(dir.join("05. e.flac"), "").unwrap();
+
+        let new_plans = || {
+            vec![
+                // Replaces the source of another track, which is moved away.
+                track_plan(dir, 1, "track2.flac"),
+                track_plan(dir, 2, "02. Intro.flac"),
+                track_plan(dir, 3, "02. intro.flac"),
+                track_plan(dir, 4, "04. D.flac"),
+                // Existing files are also compared ignoring case.
+                track_plan(dir, 5, "05. E.flac"),
+            ]
+        };
+
+        let mut plans = new_plans();
+        match resolve_collisions(&mut plans, CollisionPolicy::Abort, &sanitizer, false) {
+            Err(Error::Collisions { collisions }) => {
+                let collisions = collisions
+                    .iter()
+                    .map(|c| (c.track.as_str(), c.other_track.as_deref()))
+                    .collect::<Vec<_>>();
+                assert_eq!(collisions, vec![("3", Some("2")), ("4", None), ("5", None)]);
+            }
+            _ => panic!("expected collisions"),
+        }
+
+        let mut plans = new_plans();
+        resolve_collisions(&mut plans, CollisionPolicy::Number, &sanitizer, false).unwrap();
+        let output_file_names = plans
+            .iter()
+            .map(|p| p.output_file_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            output_file_names,
+            vec![
+                "track2.flac",
+                "02. Intro.flac",
+                "02. intro (2).flac",
+                "04. D (2).flac",
+                "05. E (2).flac",
+            ]
+        );
+        assert_eq!(plans[3].output_path, dir.join("04. D (2).flac"));
+
+        let mut plans = new_plans();
+        assert!(
+            resolve_collisions(&mut plans, CollisionPolicy::Overwrite, &sanitizer, false).is_err()
+        );
+        let mut plans = vec![track_plan(dir, 4, "04. D.flac")];
+        resolve_collisions(&mut plans, CollisionPolicy::Overwrite, &sanitizer, false).unwrap();
+
+        // Kept sources are not moved away, so they would be overwritten.
+        let mut plans = vec![track_plan(dir, 2, "track1.flac")];
+        assert!(resolve_collisions(&mut plans, CollisionPolicy::Abort, &sanitizer, true).is_err());
+    }
+
+    #[test]
+    fn test_plan_output_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let library_dir = temp_dir.path().join("library");
+        let source_dir = temp_dir.path().join("source");
+        std::fs::create_dir(&source_dir).unwrap();
+
+        let template = "{albumartist}/{date:4} - {album}".parse().unwrap();
+        let album_block = btreemap! {
+            S("ALBUM") => MetaVal::One(S("Either/Or")),
+            S("albumartist") => MetaVal::One(S("Elliott Smith")),
+            S("date") => MetaVal::One(S("1997-02-25")),
+        };
+        let album_dir = library_dir.join("Elliott Smith").join("1997 - EitherOr");
+        let sanitizer = Sanitizer::default();
+
+        let plan = |allow_merge| {
+            plan_output_dir(
+                &template,
+                &album_block,
+                &sanitizer,
+                ArtistJoin::Comma,
+                &library_dir,
+                &source_dir,
+                allow_merge,
+            )
+        };
+
+        assert_eq!(plan(false).unwrap(), album_dir);
+
+        std::fs::create_dir_all(&album_dir).unwrap();
+        assert_eq!(plan(false).unwrap(), album_dir);
+
+        std::fs::write(album_dir.join("01. Speed Trials.flac"), "").unwrap();
+        assert!(matches!(plan(false), Err(Error::OutputDir { .. })));
+        assert_eq!(plan(true).unwrap(), album_dir);
+
+        // Retagging an album that is already in place.
+        assert_eq!(
+            plan_output_dir(
+                &template,
+                &album_block,
+                &sanitizer,
+                ArtistJoin::Comma,
+                &library_dir,
+                &album_dir,
+                false
+            )
+            .unwrap(),
+            album_dir
+        );
+
+        let missing_key = "{label}/{album}".parse().unwrap();
+        assert!(matches!(
+            plan_output_dir(
+                &missing_key,
+                &album_block,
+                &sanitizer,
+                ArtistJoin::Comma,
+                &library_dir,
+                &source_dir,
+                false
+            ),
+            Err(Error::MissingAlbumKey { .. })
+        ));
+
+        let sanitizer = Sanitizer::new(SanitizePolicy::Windows, S("_"), vec![], 255);
+        assert_eq!(
+            plan_output_dir(
+                &template,
+                &album_block,
+                &sanitizer,
+                ArtistJoin::Comma,
+                &library_dir,
+                &source_dir,
+                false
+            )
+            .unwrap(),
+            library_dir.join("Elliott Smith").join("1997 - Either_Or")
+        );
+
+        for escaping in ["/{album}", "../{album}", "{album}/.."] {
+            assert!(matches!(
+                plan_output_dir(
+                    &escaping.parse().unwrap(),
+                    &album_block,
+                    &sanitizer,
+                    ArtistJoin::Comma,
+                    &library_dir,
+                    &source_dir,
+                    false
+                ),
+                Err(Error::OutputDir { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_merge_tags__multi_disc() {
+        let album_block = btreemap! {
+            S("date") => MetaVal::One(S("1999")),
+            S("discnumber") => MetaVal::One(S("1")),
+        };
+        let disc_block = btreemap! {
+            S("date") => MetaVal::One(S("2001")),
+            S("discsubtitle") => MetaVal::One(S("Live")),
+        };
+
+        let position = Position {
+            track: 5,
+            total_tracks: 9,
+            disc: Some((2, 3)),
+        };
+
+        let expected = btreemap! {
+            S("date") => MetaVal::One(S("2001")),
+            S("discnumber") => MetaVal::One(S("2")),
+            S("disctotal") => MetaVal::One(S("3")),
+            S("discsubtitle") => MetaVal::One(S("Live")),
+            S("totaltracks") => MetaVal::One(S("9")),
+            S("tracknumber") => MetaVal::One(S("5")),
+        };
+
+        assert_eq!(
+            merge_tags(
+                MetaBlock::new(),
+                &album_block,
+                Some(&disc_block),
+                &MetaBlock::new(),
+                &position
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_plan_tracks__lone_disc() {
+        let track = Track {
+            index: 1,
+            disc: Some(2),
+            path: PathBuf::from("01. Title.flac"),
+            tag: Tag::new(),
+        };
+        let metadata = Metadata {
+            album: btreemap! {
+                S("artist") => MetaVal::One(S("Artist")),
+            },
+            discs: vec![btreemap! {
+                S("discsubtitle") => MetaVal::One(S("Bonus")),
+            }],
+            tracks: vec![btreemap! {
+                S("title") => MetaVal::One(S("Title")),
+            }],
+        };
+
+        let plans = plan_tracks(
+            vec![track],
+            &metadata,
+            &PreservePolicy::default(),
+            None,
+            &Sanitizer::default(),
+            ArtistJoin::default(),
+            Path::new("out"),
+        )
+        .unwrap();
+
+        assert_eq!(plans[0].tags["discnumber"], MetaVal::One(S("2")));
+        assert_eq!(plans[0].tags["disctotal"], MetaVal::One(S("2")));
+        assert_eq!(plans[0].tags["discsubtitle"], MetaVal::One(S("Bonus")));
+        assert_eq!(plans[0].output_file_name, "2-1. Artist - Title.flac");
+    }
+}
@@ -0,0 +1,117 @@
+use glob::{MatchOptions, Pattern};
+use metaflac::Tag;
+
+use crate::metadata::{MetaBlock, MetaVal};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which of the existing vorbis comments of a track survive a retag,
+/// using glob patterns on their keys. Keys are matched regardless of case.
+#[derive(Debug, Default)]
+pub(crate) struct PreservePolicy {
+    keep: Vec<Pattern>,
+    drop: Vec<Pattern>,
+}
+
+impl PreservePolicy {
+    pub fn new(keep: Vec<Pattern>, drop: Vec<Pattern>) -> Self {
+        Self { keep, drop }
+    }
+
+    /// Whether an existing comment with this key survives. Comments matching
+    /// a drop pattern never survive. Otherwise, comments survive if they match
+    /// a keep pattern, or if only drop patterns were given.
+    pub fn keeps(&self, key: &str) -> bool {
+        let matches_any = |patterns: &[Pattern]| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_with(key, MATCH_OPTIONS))
+        };
+
+        if matches_any(&self.drop) {
+            return false;
+        }
+
+        if self.keep.is_empty() {
+            !self.drop.is_empty()
+        } else {
+            matches_any(&self.keep)
+        }
+    }
+
+    /// Collects the existing comments of a track that survive, with keys
+    /// lowercased.
+    pub fn preserved_comments(&self, tag: &Tag) -> MetaBlock {
+        let mut preserved = MetaBlock::new();
+
+        let comments = tag
+            .vorbis_comments()
+            .into_iter()
+            .flat_map(|vc| vc.comments.iter());
+
+        for (key, values) in comments {
+            if !self.keeps(key) || values.is_empty() {
+                continue;
+            }
+
+            let mut values = values.clone();
+            let key = key.to_ascii_lowercase();
+
+            // Keys that only differ in case are the same field.
+            if let Some(existing) = preserved.remove(&key) {
+                let mut existing = existing.into_vec();
+                existing.append(&mut values);
+                values = existing;
+            }
+
+            let meta_val = if values.len() == 1 {
+                MetaVal::One(values.swap_remove(0))
+            } else {
+                MetaVal::Many(values)
+            };
+
+            preserved.insert(key, meta_val);
+        }
+
+        preserved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<Pattern> {
+        patterns.iter().map(|p| Pattern::new(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_preserve_policy__keeps() {
+        let policy = PreservePolicy::default();
+        assert!(!policy.keeps("isrc"));
+
+        let policy = PreservePolicy::new(patterns(&["isrc", "musicbrainz_*"]), vec![]);
+        assert!(policy.keeps("ISRC"));
+        assert!(policy.keeps("MUSICBRAINZ_TRACKID"));
+        assert!(policy.keeps("musicbrainz_albumid"));
+        assert!(!policy.keeps("encoder"));
+
+        let policy = PreservePolicy::new(
+            patterns(&["musicbrainz_*"]),
+            patterns(&["musicbrainz_album*"]),
+        );
+        assert!(policy.keeps("MUSICBRAINZ_TRACKID"));
+        assert!(!policy.keeps("MUSICBRAINZ_ALBUMID"));
+
+        let policy = PreservePolicy::new(vec![], patterns(&["comment", "replaygain_*"]));
+        assert!(policy.keeps("ISRC"));
+        assert!(!policy.keeps("COMMENT"));
+        assert!(!policy.keeps("REPLAYGAIN_TRACK_GAIN"));
+    }
+}
@@ -1,9 +1,14 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
 
+use clap::ValueEnum;
 use metaflac::Tag;
 
+use crate::error::{Error, Result};
 use crate::helpers::{self, Track};
 use crate::metadata::{MetaBlock, MetaBlockList, MetaVal, Metadata};
 
@@ -32,37 +37,79 @@ const SKIPPED_TAGS: &[&str] = &[
     "year",
 ];
 
-pub(crate) fn load_metadata(path: &Path) -> Metadata {
-    println!("Loading incoming metadata file: {}", path.display());
+/// Which source of track and disc numbers takes precedence when ordering the
+/// tracks of an album. Tracks that are not numbered by either source are
+/// ordered by their file names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum TrackOrder {
+    /// The `tracknumber` and `discnumber` tags, falling back to a leading
+    /// number in the file name.
+    #[default]
+    Tags,
+    /// A leading number in the file name, falling back to the tags.
+    FileNames,
+}
+
+/// The track and disc numbers of a track, as far as a single source knows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Numbering {
+    disc: Option<usize>,
+    track: Option<usize>,
+}
+
+impl Numbering {
+    /// Fills in the numbers that are missing from this numbering.
+    fn or(self, other: Self) -> Self {
+        Self {
+            disc: self.disc.or(other.disc),
+            track: self.track.or(other.track),
+        }
+    }
+}
 
-    let contents = std::fs::read_to_string(path).unwrap();
-    serde_json::from_str(&contents).unwrap()
+/// Reads and parses a JSON file.
+fn load_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let contents = std::fs::read_to_string(path).map_err(Error::io(path))?;
+    serde_json::from_str(&contents).map_err(Error::json(path))
 }
 
-pub(crate) fn load_split_metadata(album_path: &Path, track_path: &Path) -> Metadata {
-    println!(
+pub(crate) fn load_split_metadata(
+    album_path: &Path,
+    disc_path: Option<&Path>,
+    track_path: &Path,
+) -> Result<Metadata> {
+    eprintln!(
         "Loading incoming metadata files (album, track): ({}, {})",
         album_path.display(),
         track_path.display(),
     );
 
-    let contents = std::fs::read_to_string(album_path).unwrap();
-    let album_block: MetaBlock = serde_json::from_str(&contents).unwrap();
+    let album_block: MetaBlock = load_json(album_path)?;
+    let track_blocks: MetaBlockList = load_json(track_path)?;
 
-    let contents = std::fs::read_to_string(track_path).unwrap();
-    let track_blocks: MetaBlockList = serde_json::from_str(&contents).unwrap();
+    let disc_blocks: MetaBlockList = match disc_path {
+        Some(disc_path) => {
+            eprintln!(
+                "Loading incoming disc metadata file: {}",
+                disc_path.display()
+            );
+            load_json(disc_path)?
+        }
+        None => Vec::new(),
+    };
 
-    Metadata {
+    Ok(Metadata {
         album: album_block,
+        discs: disc_blocks,
         tracks: track_blocks,
-    }
+    })
 }
 
 pub(crate) fn emit_existing_tags<'a>(
     tags: impl Iterator<Item = &'a Tag>,
     emit_stdout: bool,
     emit_fp: Option<&Path>,
-) {
+) -> Result<()> {
     let mut pe_blocks = Vec::new();
     let mut count = 0usize;
 
@@ -71,12 +118,16 @@ pub(crate) fn emit_existing_tags<'a>(
 
         let mut pe_block = MetaBlock::new();
 
-        let keys = tag.vorbis_comments().unwrap().comments.keys();
+        // Files without a comment block have no tags to emit.
+        let keys = tag
+            .vorbis_comments()
+            .into_iter()
+            .flat_map(|vc| vc.comments.keys());
 
         for key in keys {
             let key = key.to_ascii_lowercase();
             if !SKIPPED_TAGS.contains(&key.as_str()) {
-                tag.get_vorbis(&key).map(|v| {
+                if let Some(v) = tag.get_vorbis(&key) {
                     let mut vals = v.map(String::from).collect::<Vec<_>>();
 
                     let meta_val = if vals.len() == 1 {
@@ -86,7 +137,7 @@ pub(crate) fn emit_existing_tags<'a>(
                     };
 
                     pe_block.insert(key, meta_val);
-                });
+                }
             }
         }
 
@@ -94,7 +145,8 @@ pub(crate) fn emit_existing_tags<'a>(
     }
 
     // Serialize existing blocks to a string.
-    let json_str = serde_json::to_string_pretty(&pe_blocks).unwrap();
+    let json_str =
+        serde_json::to_string_pretty(&pe_blocks).expect("tag blocks are always serializable");
 
     if emit_stdout {
         println!(
@@ -103,59 +155,327 @@ pub(crate) fn emit_existing_tags<'a>(
         );
         println!("----------------------------------------------------------------");
         println!("{}", json_str);
-        println!("");
+        println!();
         println!("----------------------------------------------------------------");
     }
 
     // Emit the existing blocks to a file, if provided.
-    emit_fp.map(|fp| std::fs::write(fp, &json_str).unwrap());
+    if let Some(fp) = emit_fp {
+        std::fs::write(fp, &json_str).map_err(Error::io(fp))?;
+    }
 
     // Pause for user input.
-    helpers::pause();
+    helpers::pause().map_err(Error::io(Path::new("<stdin>")))
 }
 
-pub(crate) fn collect_tracks(
-    source_dir: &Path,
-    emit_existing: bool,
-    emit_existing_to: Option<&Path>,
-) -> Vec<Track> {
-    let track_paths = source_dir
-        .read_dir()
-        .unwrap()
-        .map(|e| e.unwrap().path())
-        .filter(|p| p.extension() == Some(OsStr::new("flac")))
-        .collect::<Vec<_>>();
+/// Parses the disc number from the name of a disc subfolder, such as `CD1`,
+/// `Disc 2` or `disk_03`.
+fn parse_disc_folder_name(name: &str) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+    let rest = ["disc", "disk", "cd"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))?;
+    let digits = rest.trim_start_matches([' ', '_', '-', '.']);
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
 
-    let mut expected_track_nums = (1..=track_paths.len()).collect::<HashSet<_>>();
+    digits.parse().ok().filter(|n| *n > 0)
+}
+
+/// Parses a track or disc number tag value, which may be zero-padded and may
+/// include the total (e.g. `03` or `3/12`).
+fn parse_number_tag(value: &str) -> Option<usize> {
+    let number = value.split('/').next()?.trim();
+    number.parse().ok().filter(|n| *n > 0)
+}
+
+/// Reads the track and disc numbers from the tags of a track. Tags that are
+/// present but cannot be parsed are an error.
+fn read_tag_numbering(tag: &Tag, path: &Path) -> Result<Numbering> {
+    let read_number = |key: &str| -> std::result::Result<Option<usize>, String> {
+        let values = match tag.get_vorbis(key) {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+        let value = helpers::expect_one(values)
+            .ok_or_else(|| String::from("expected exactly one value"))?;
+
+        parse_number_tag(value)
+            .map(Some)
+            .ok_or_else(|| format!("'{}' is not a number", value))
+    };
+
+    let track = read_number("tracknumber").map_err(|reason| Error::InvalidTrackNumber {
+        path: path.to_path_buf(),
+        reason,
+    })?;
+    let disc = read_number("discnumber").map_err(|reason| Error::InvalidDiscNumber {
+        path: path.to_path_buf(),
+        reason,
+    })?;
+
+    Ok(Numbering { disc, track })
+}
 
-    let mut tracks = Vec::with_capacity(track_paths.len());
+/// Parses the leading number of a file name, such as `03 - Title.flac`. A
+/// leading `disc-track` pair, such as `2-03 Title.flac`, also gives the disc.
+fn parse_file_name_numbering(file_name: &str) -> Numbering {
+    let mut chars = file_name.chars().peekable();
 
-    for track_path in track_paths {
-        println!("Found input file: {}", track_path.display());
-        let track_tag = Tag::read_from_path(&track_path).unwrap();
+    let parse = |digits: String| digits.parse().ok().filter(|n: &usize| *n > 0);
 
-        let track_num_str = helpers::expect_one(track_tag.get_vorbis("tracknumber").unwrap());
-        let track_num = track_num_str.parse::<usize>().unwrap();
+    let first = take_digits(&mut chars);
+    if first.is_empty() {
+        return Numbering::default();
+    }
 
-        assert!(
-            expected_track_nums.remove(&track_num),
-            "unexpected track number"
-        );
+    if chars.next_if_eq(&'-').is_some() {
+        let second = take_digits(&mut chars);
+        if !second.is_empty() {
+            return Numbering {
+                disc: parse(first),
+                track: parse(second),
+            };
+        }
+    }
+
+    Numbering {
+        disc: None,
+        track: parse(first),
+    }
+}
+
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+
+    digits
+}
+
+/// Compares two strings in natural order, where runs of digits are compared
+/// by their numeric value (e.g. `Track 2` comes before `Track 10`), and other
+/// characters regardless of case.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_digits(&mut a_chars);
+                let y = take_digits(&mut b_chars);
+                let x = x.trim_start_matches('0');
+                let y = y.trim_start_matches('0');
+
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                a_chars.next();
+                b_chars.next();
 
-        let track = Track {
-            index: track_num,
-            path: track_path,
-            tag: track_tag,
+                ordering
+            }
         };
 
-        tracks.push(track);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Finds the FLAC files in the source directory and in its disc subfolders,
+/// along with the disc number implied by the subfolder they are in.
+fn find_track_paths(source_dir: &Path) -> Result<Vec<(PathBuf, Option<usize>)>> {
+    let mut track_paths = Vec::new();
+    let mut disc_dirs = Vec::new();
+
+    for entry in source_dir.read_dir().map_err(Error::io(source_dir))? {
+        let path = entry.map_err(Error::io(source_dir))?.path();
+
+        if path.is_dir() {
+            let disc_num = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(parse_disc_folder_name);
+
+            if let Some(disc_num) = disc_num {
+                disc_dirs.push((path, disc_num));
+            }
+        } else if path.extension() == Some(OsStr::new("flac")) {
+            track_paths.push((path, None));
+        }
+    }
+
+    for (disc_dir, disc_num) in disc_dirs {
+        for entry in disc_dir.read_dir().map_err(Error::io(&disc_dir))? {
+            let path = entry.map_err(Error::io(&disc_dir))?.path();
+
+            if path.extension() == Some(OsStr::new("flac")) {
+                track_paths.push((path, Some(disc_num)));
+            }
+        }
+    }
+
+    Ok(track_paths)
+}
+
+/// Finds the discs that are missing from the sorted disc numbers of a
+/// release. A single disc is never incomplete, even if it is not disc 1, as it
+/// may be one disc of a box set that is split across folders.
+fn find_missing_discs(disc_nums: &[usize]) -> Vec<usize> {
+    match disc_nums {
+        [] | [_] => Vec::new(),
+        [.., max] => (1..*max).filter(|n| !disc_nums.contains(n)).collect(),
+    }
+}
+
+/// Checks the track numbers on each disc, and orders the tracks by disc and
+/// track number.
+fn number_discs(discs: BTreeMap<usize, Vec<Track>>) -> Result<Vec<Track>> {
+    // Ensure that the discs are numbered without gaps.
+    let missing_discs = find_missing_discs(&discs.keys().copied().collect::<Vec<_>>());
+    if !missing_discs.is_empty() {
+        return Err(Error::MissingDiscs {
+            discs: missing_discs,
+        });
+    }
+
+    let is_multi_disc = discs.len() > 1;
+    let mut tracks = Vec::new();
+
+    for (disc_num, disc_tracks) in discs {
+        let mut expected_track_nums = (1..=disc_tracks.len()).collect::<HashSet<_>>();
+
+        for track in disc_tracks.iter() {
+            if !expected_track_nums.remove(&track.index) {
+                return Err(Error::UnexpectedTrackNumber {
+                    path: track.path.clone(),
+                    disc: disc_num,
+                    track: track.index,
+                });
+            }
+        }
+
+        // Ensure that all expected track numbers were covered.
+        if !expected_track_nums.is_empty() {
+            let mut missing = expected_track_nums.into_iter().collect::<Vec<_>>();
+            missing.sort_unstable();
+
+            return Err(Error::MissingTrackNumbers {
+                disc: disc_num,
+                tracks: missing,
+            });
+        }
+
+        // Disc numbers are kept for releases with more than one disc, and for
+        // a lone disc that is not disc 1.
+        let disc = Some(disc_num).filter(|&n| is_multi_disc || n != 1);
+        tracks.extend(disc_tracks.into_iter().map(|track| Track { disc, ..track }));
     }
 
-    // Ensure that all expected track numbers were covered.
-    assert!(expected_track_nums.is_empty());
+    // Sort the tracks by disc and track number.
+    tracks.sort_by_key(|t| (t.disc, t.index));
+
+    Ok(tracks)
+}
+
+/// Works out the track and disc numbers of a track from the preferred source,
+/// filling in what it lacks from the other one. With file names preferred,
+/// broken tags are only an error if they are actually needed.
+fn resolve_numbering(tag: &Tag, path: &Path, track_order: TrackOrder) -> Result<Numbering> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let file_name_numbering = parse_file_name_numbering(&file_name);
+
+    match track_order {
+        TrackOrder::Tags => Ok(read_tag_numbering(tag, path)?.or(file_name_numbering)),
+        TrackOrder::FileNames => match read_tag_numbering(tag, path) {
+            Ok(tag_numbering) => Ok(file_name_numbering.or(tag_numbering)),
+            Err(_) if file_name_numbering.track.is_some() => Ok(file_name_numbering),
+            Err(err) => Err(err),
+        },
+    }
+}
 
-    // Sort the tracks by track number.
-    tracks.sort_by_key(|t| t.index);
+/// Collects the tracks of an album, which may span multiple discs. Track and
+/// disc numbers are taken from the tags and file names, in the given order of
+/// preference, and the disc may also be implied by the subfolder a track is
+/// in. Tracks without a number are ordered by file name, and take up the
+/// numbers left over on their disc. On each disc, the track numbers must be
+/// exactly 1 to the number of tracks on that disc.
+pub(crate) fn collect_tracks(
+    source_dir: &Path,
+    track_order: TrackOrder,
+    emit_existing: bool,
+    emit_existing_to: Option<&Path>,
+) -> Result<Vec<Track>> {
+    let track_paths = find_track_paths(source_dir)?;
+
+    let mut numbered_discs = BTreeMap::<usize, Vec<(Option<usize>, PathBuf, Tag)>>::new();
+
+    for (track_path, folder_disc_num) in track_paths {
+        eprintln!("Found input file: {}", track_path.display());
+        let track_tag = Tag::read_from_path(&track_path).map_err(Error::tag(&track_path))?;
+
+        let numbering = resolve_numbering(&track_tag, &track_path, track_order)?;
+        let disc_num = numbering.disc.or(folder_disc_num).unwrap_or(1);
+
+        numbered_discs
+            .entry(disc_num)
+            .or_default()
+            .push((numbering.track, track_path, track_tag));
+    }
+
+    let mut discs = BTreeMap::<usize, Vec<Track>>::new();
+
+    for (disc_num, disc_tracks) in numbered_discs {
+        let (numbered, mut unnumbered): (Vec<_>, Vec<_>) = disc_tracks
+            .into_iter()
+            .partition(|(track_num, _, _)| track_num.is_some());
+
+        let taken = numbered
+            .iter()
+            .filter_map(|(track_num, _, _)| *track_num)
+            .collect::<HashSet<_>>();
+        let mut free_track_nums = (1..).filter(|n| !taken.contains(n));
+
+        unnumbered.sort_by(|(_, a, _), (_, b, _)| {
+            natural_cmp(&a.to_string_lossy(), &b.to_string_lossy())
+        });
+
+        let unnumbered = unnumbered.into_iter().map(|(_, path, tag)| {
+            let track_num = free_track_nums.next().unwrap_or_default();
+            eprintln!(
+                "No track number found for {}, ordering it as track {} by file name",
+                path.display(),
+                track_num
+            );
+            (Some(track_num), path, tag)
+        });
+
+        let tracks = numbered
+            .into_iter()
+            .chain(unnumbered.collect::<Vec<_>>())
+            .map(|(track_num, path, tag)| Track {
+                index: track_num.unwrap_or_default(),
+                disc: Some(disc_num),
+                path,
+                tag,
+            })
+            .collect();
+
+        discs.insert(disc_num, tracks);
+    }
+
+    let tracks = number_discs(discs)?;
 
     // Emit existing tags, if requested.
     if emit_existing || emit_existing_to.is_some() {
@@ -163,8 +483,152 @@ pub(crate) fn collect_tracks(
             tracks.iter().map(|t| &t.tag),
             emit_existing,
             emit_existing_to,
+        )?;
+    }
+
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use maplit::btreemap;
+
+    #[test]
+    fn test_parse_disc_folder_name() {
+        assert_eq!(parse_disc_folder_name("CD1"), Some(1));
+        assert_eq!(parse_disc_folder_name("Disc 2"), Some(2));
+        assert_eq!(parse_disc_folder_name("disk_03"), Some(3));
+        assert_eq!(parse_disc_folder_name("cd-10"), Some(10));
+
+        assert_eq!(parse_disc_folder_name("CD"), None);
+        assert_eq!(parse_disc_folder_name("CD0"), None);
+        assert_eq!(parse_disc_folder_name("Disc 2 (Bonus)"), None);
+        assert_eq!(parse_disc_folder_name("Scans"), None);
+    }
+
+    #[test]
+    fn test_parse_number_tag() {
+        assert_eq!(parse_number_tag("2"), Some(2));
+        assert_eq!(parse_number_tag("2/3"), Some(2));
+        assert_eq!(parse_number_tag(" 1 / 2 "), Some(1));
+        assert_eq!(parse_number_tag("03"), Some(3));
+        assert_eq!(parse_number_tag("03/12"), Some(3));
+
+        assert_eq!(parse_number_tag("0"), None);
+        assert_eq!(parse_number_tag("A"), None);
+        assert_eq!(parse_number_tag("A1"), None);
+        assert_eq!(parse_number_tag("/2"), None);
+    }
+
+    #[test]
+    fn test_parse_file_name_numbering() {
+        let numbering = |disc, track| Numbering { disc, track };
+
+        assert_eq!(
+            parse_file_name_numbering("03 - Title.flac"),
+            numbering(None, Some(3))
+        );
+        assert_eq!(
+            parse_file_name_numbering("12. Title.flac"),
+            numbering(None, Some(12))
+        );
+        assert_eq!(
+            parse_file_name_numbering("2-03 Title.flac"),
+            numbering(Some(2), Some(3))
+        );
+        assert_eq!(
+            parse_file_name_numbering("01-Title.flac"),
+            numbering(None, Some(1))
+        );
+
+        assert_eq!(
+            parse_file_name_numbering("Title.flac"),
+            Numbering::default()
+        );
+        assert_eq!(
+            parse_file_name_numbering("00 Intro.flac"),
+            Numbering::default()
         );
     }
 
-    tracks
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "Track 10.flac",
+            "track 2.flac",
+            "Track 1.flac",
+            "Bonus.flac",
+            "Track 02b.flac",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            vec![
+                "Bonus.flac",
+                "Track 1.flac",
+                "track 2.flac",
+                "Track 02b.flac",
+                "Track 10.flac",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_missing_discs() {
+        assert_eq!(find_missing_discs(&[1, 2, 3]), Vec::<usize>::new());
+        assert_eq!(find_missing_discs(&[1, 3, 5]), vec![2, 4]);
+        assert_eq!(find_missing_discs(&[2, 3]), vec![1]);
+
+        // A single disc of a split box set is accepted as is.
+        assert_eq!(find_missing_discs(&[2]), Vec::<usize>::new());
+        assert_eq!(find_missing_discs(&[]), Vec::<usize>::new());
+    }
+
+    fn track(disc: usize, index: usize) -> Track {
+        Track {
+            index,
+            disc: Some(disc),
+            path: PathBuf::from(format!("{}-{}.flac", disc, index)),
+            tag: Tag::new(),
+        }
+    }
+
+    #[test]
+    fn test_number_discs() {
+        let discs = btreemap! {
+            1 => vec![track(1, 2), track(1, 1)],
+        };
+        let tracks = number_discs(discs).unwrap();
+        assert_eq!(
+            tracks.iter().map(|t| (t.disc, t.index)).collect::<Vec<_>>(),
+            vec![(None, 1), (None, 2)]
+        );
+
+        let discs = btreemap! {
+            1 => vec![track(1, 1)],
+            2 => vec![track(2, 1)],
+        };
+        let tracks = number_discs(discs).unwrap();
+        assert_eq!(
+            tracks.iter().map(|t| (t.disc, t.index)).collect::<Vec<_>>(),
+            vec![(Some(1), 1), (Some(2), 1)]
+        );
+    }
+
+    #[test]
+    fn test_number_discs__lone_disc() {
+        let discs = btreemap! {
+            2 => vec![track(2, 1)],
+        };
+        let tracks = number_discs(discs).unwrap();
+        assert_eq!(
+            tracks.iter().map(|t| (t.disc, t.index)).collect::<Vec<_>>(),
+            vec![(Some(2), 1)]
+        );
+    }
 }
@@ -0,0 +1,129 @@
+use serde::Serialize;
+
+use crate::helpers;
+use crate::loudness::{AnalysisOutput, Measurement, ReferenceLevel};
+
+/// Converts a linear peak level to dB relative to full scale.
+fn peak_to_db(peak: f32) -> f32 {
+    20.0 * peak.log10()
+}
+
+/// Wraps a value in `Some` only if it is finite, so that unmeasurable values
+/// (e.g. the loudness of silence) are reported as missing.
+fn finite(value: f32) -> Option<f32> {
+    Some(value).filter(|v| v.is_finite())
+}
+
+/// Formats an optional value with a unit, or a dash if it is missing.
+fn format_value(value: Option<f32>, unit: &str) -> String {
+    match value {
+        Some(v) => format!("{:.2} {}", v, unit),
+        None => String::from("-"),
+    }
+}
+
+/// Loudness values for a single track or album, in the units used by the
+/// report.
+#[derive(Debug, Serialize)]
+pub(crate) struct MeasurementReport {
+    integrated_lufs: Option<f32>,
+    gain_db: Option<f32>,
+    range_lu: f32,
+    max_momentary_lufs: Option<f32>,
+    max_short_term_lufs: Option<f32>,
+    sample_peak: f32,
+    true_peak: f32,
+    true_peak_dbtp: Option<f32>,
+}
+
+impl MeasurementReport {
+    fn new(measurement: &Measurement, reference: ReferenceLevel) -> Self {
+        Self {
+            integrated_lufs: finite(measurement.loudness.lkfs()),
+            gain_db: finite(measurement.loudness.gain(reference)),
+            range_lu: measurement.range,
+            max_momentary_lufs: finite(measurement.max_momentary.lkfs()),
+            max_short_term_lufs: finite(measurement.max_short_term.lkfs()),
+            sample_peak: measurement.sample_peak,
+            true_peak: measurement.true_peak,
+            true_peak_dbtp: finite(peak_to_db(measurement.true_peak)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TrackReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disc: Option<usize>,
+    track: usize,
+    file: String,
+    #[serde(flatten)]
+    measurement: MeasurementReport,
+}
+
+/// A summary of the loudness analysis of an album and its tracks.
+#[derive(Debug, Serialize)]
+pub(crate) struct LoudnessReport {
+    reference_lufs: f32,
+    tracks: Vec<TrackReport>,
+    album: MeasurementReport,
+}
+
+impl LoudnessReport {
+    pub fn new(analysis_output: &AnalysisOutput, reference: ReferenceLevel) -> Self {
+        let tracks = analysis_output
+            .scanned_tracks
+            .iter()
+            .map(|scanned_track| TrackReport {
+                disc: scanned_track.track.disc,
+                track: scanned_track.track.index,
+                file: scanned_track
+                    .track
+                    .path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                measurement: MeasurementReport::new(&scanned_track.measurement, reference),
+            })
+            .collect();
+
+        Self {
+            reference_lufs: reference.lkfs(),
+            tracks,
+            album: MeasurementReport::new(&analysis_output.album, reference),
+        }
+    }
+
+    fn print_row(label: &str, measurement: &MeasurementReport, file: &str) {
+        println!(
+            "{:>5}  {:>12}  {:>11}  {:>10}  {:>8}  {}",
+            label,
+            format_value(measurement.integrated_lufs, "LUFS"),
+            format_value(measurement.true_peak_dbtp, "dBTP"),
+            format_value(measurement.gain_db, "dB"),
+            format_value(Some(measurement.range_lu), "LU"),
+            file,
+        );
+    }
+
+    pub fn print_table(&self) {
+        println!(
+            "{:>5}  {:>12}  {:>11}  {:>10}  {:>8}  File",
+            "Track", "Integrated", "True peak", "Gain", "Range",
+        );
+
+        for track in &self.tracks {
+            Self::print_row(
+                &helpers::format_position(track.disc, track.track),
+                &track.measurement,
+                &track.file,
+            );
+        }
+
+        Self::print_row("Album", &self.album, "");
+
+        println!();
+        println!("Gains are relative to {:.2} LUFS.", self.reference_lufs);
+    }
+}
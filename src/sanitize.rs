@@ -0,0 +1,323 @@
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that Windows does not allow in file names.
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Device names that Windows reserves, even when followed by an extension.
+const WINDOWS_RESERVED: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Which file systems output names must be valid on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SanitizePolicy {
+    /// Only `/` and NUL are replaced.
+    #[default]
+    Posix,
+    /// Also replaces the characters that Windows and SMB shares forbid, and
+    /// avoids reserved device names and trailing dots and spaces.
+    Windows,
+    /// Like `windows`, but also strips accents and replaces any remaining
+    /// non-ASCII characters.
+    StrictAscii,
+}
+
+/// A replacement for a single forbidden character, given as `C=REPLACEMENT`
+/// (e.g. `:=-`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CharReplacement {
+    pub from: char,
+    pub to: String,
+}
+
+impl FromStr for CharReplacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(from), Some('=')) => Ok(Self {
+                from,
+                to: chars.collect(),
+            }),
+            _ => Err(format!("expected CHAR=REPLACEMENT, got '{}'", s)),
+        }
+    }
+}
+
+/// Turns rendered names into file and directory names that are valid under
+/// a sanitization policy. Names are normalized to Unicode NFC, and truncated
+/// to a maximum length in bytes.
+#[derive(Debug)]
+pub(crate) struct Sanitizer {
+    policy: SanitizePolicy,
+    /// The replacement for forbidden characters without a specific one.
+    replacement: String,
+    char_replacements: Vec<CharReplacement>,
+    max_name_bytes: usize,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self::new(SanitizePolicy::default(), String::new(), Vec::new(), 255)
+    }
+}
+
+impl Sanitizer {
+    pub fn new(
+        policy: SanitizePolicy,
+        replacement: String,
+        char_replacements: Vec<CharReplacement>,
+        max_name_bytes: usize,
+    ) -> Self {
+        Self {
+            policy,
+            replacement,
+            char_replacements,
+            max_name_bytes,
+        }
+    }
+
+    fn is_forbidden(&self, c: char) -> bool {
+        match self.policy {
+            SanitizePolicy::Posix => c == '/' || c == '\0',
+            SanitizePolicy::Windows => c.is_ascii_control() || WINDOWS_FORBIDDEN.contains(&c),
+            SanitizePolicy::StrictAscii => {
+                !c.is_ascii() || c.is_ascii_control() || WINDOWS_FORBIDDEN.contains(&c)
+            }
+        }
+    }
+
+    fn replacement_for(&self, c: char) -> &str {
+        self.char_replacements
+            .iter()
+            .find(|r| r.from == c)
+            .map_or(self.replacement.as_str(), |r| r.to.as_str())
+    }
+
+    /// The replacement for `/` in values that are filled into templates, so
+    /// that they cannot add levels to a path.
+    pub fn separator_replacement(&self) -> String {
+        self.replace_chars(self.replacement_for('/'))
+    }
+
+    /// Replaces the forbidden characters of a string. Forbidden characters in
+    /// the replacements themselves are dropped.
+    fn replace_chars(&self, s: &str) -> String {
+        let mut replaced = String::with_capacity(s.len());
+
+        for c in s.chars() {
+            if self.is_forbidden(c) {
+                let replacement = self.replacement_for(c);
+                replaced.extend(replacement.chars().filter(|c| !self.is_forbidden(*c)));
+            } else {
+                replaced.push(c);
+            }
+        }
+
+        replaced
+    }
+
+    fn normalize(&self, s: &str) -> String {
+        match self.policy {
+            // Decompose accented characters, and keep only their base.
+            SanitizePolicy::StrictAscii => s.nfkd().filter(|c| !is_combining_mark(*c)).collect(),
+            _ => s.nfc().collect(),
+        }
+    }
+
+    /// Removes what Windows strips from the end of names, and renames
+    /// reserved device names, keeping the name within `max_bytes`.
+    fn fix_windows_name(&self, name: &mut String, max_bytes: usize) {
+        if self.policy == SanitizePolicy::Posix {
+            return;
+        }
+
+        let trimmed_len = name.trim_end_matches(&['.', ' '][..]).len();
+        name.truncate(trimmed_len);
+
+        let base = name.split('.').next().unwrap_or_default().trim_end();
+        if WINDOWS_RESERVED.contains(&base.to_ascii_lowercase().as_str()) {
+            // Reserved names are ASCII, so the underscore can also take the
+            // place of their last character when the name is already full.
+            let base_len = base.len();
+            if name.len() < max_bytes {
+                name.insert(base_len, '_');
+            } else {
+                name.replace_range(base_len - 1..base_len, "_");
+            }
+        }
+    }
+
+    /// Truncates a string to at most `max_bytes`, on a character boundary.
+    fn truncate(s: &mut String, max_bytes: usize) {
+        if s.len() > max_bytes {
+            let mut end = max_bytes;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            s.truncate(end);
+        }
+    }
+
+    fn sanitize_name(&self, name: &str, max_bytes: usize) -> String {
+        let mut name = self.replace_chars(&self.normalize(name));
+
+        // The names of the current and parent directory cannot be used.
+        if name == "." || name == ".." {
+            name = name.replace('.', "_");
+        }
+
+        Self::truncate(&mut name, max_bytes);
+        self.fix_windows_name(&mut name, max_bytes);
+
+        if name.is_empty() {
+            name.push('_');
+        }
+
+        name
+    }
+
+    /// Sanitizes a single directory name.
+    pub fn sanitize_dir_name(&self, name: &str) -> String {
+        self.sanitize_name(name, self.max_name_bytes)
+    }
+
+    /// Adds a suffix such as ` (2)` to the stem of a sanitized file name,
+    /// shortening the stem further if needed to stay within the byte limit.
+    pub fn add_suffix(&self, file_name: &str, suffix: &str) -> String {
+        let (mut stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) => (String::from(stem), format!(".{}", ext)),
+            None => (String::from(file_name), String::new()),
+        };
+
+        let max_stem_bytes = self.max_name_bytes.saturating_sub(suffix.len() + ext.len());
+        Self::truncate(&mut stem, max_stem_bytes);
+
+        format!("{}{}{}", stem, suffix, ext)
+    }
+
+    /// Sanitizes a file name. Only the stem is truncated, so that the
+    /// extension is always kept.
+    pub fn sanitize_file_name(&self, stem: &str, ext: &str) -> String {
+        let ext = self.sanitize_name(ext, self.max_name_bytes);
+        let max_stem_bytes = self.max_name_bytes.saturating_sub(ext.len() + 1);
+
+        format!("{}.{}", self.sanitize_name(stem, max_stem_bytes), ext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use big_s::S;
+
+    fn sanitizer(policy: SanitizePolicy) -> Sanitizer {
+        Sanitizer::new(policy, S("_"), vec![":= -".parse().unwrap()], 255)
+    }
+
+    #[test]
+    fn test_char_replacement__from_str() {
+        assert_eq!(
+            ":=-".parse(),
+            Ok(CharReplacement {
+                from: ':',
+                to: S("-")
+            })
+        );
+        assert_eq!(
+            "?=".parse(),
+            Ok(CharReplacement {
+                from: '?',
+                to: S("")
+            })
+        );
+        assert!("?".parse::<CharReplacement>().is_err());
+        assert!("ab=c".parse::<CharReplacement>().is_err());
+    }
+
+    #[test]
+    fn test_sanitizer__posix() {
+        let sanitizer = Sanitizer::default();
+
+        assert_eq!(
+            sanitizer.sanitize_file_name("01. AC/DC - What?", "flac"),
+            "01. ACDC - What?.flac"
+        );
+        assert_eq!(sanitizer.sanitize_dir_name("CON."), "CON.");
+        assert_eq!(sanitizer.sanitize_dir_name(".."), "__");
+
+        // Decomposed characters are composed.
+        assert_eq!(
+            sanitizer.sanitize_dir_name("Beyonce\u{301}"),
+            "Beyonc\u{e9}"
+        );
+    }
+
+    #[test]
+    fn test_sanitizer__windows() {
+        let sanitizer = sanitizer(SanitizePolicy::Windows);
+
+        assert_eq!(
+            sanitizer.sanitize_file_name("01. Title: \"Why?\" <*|> ...", "flac"),
+            "01. Title - _Why__ ____.flac"
+        );
+        assert_eq!(sanitizer.sanitize_dir_name("Vol. 2. "), "Vol. 2");
+        assert_eq!(sanitizer.sanitize_dir_name("con"), "con_");
+        assert_eq!(sanitizer.sanitize_file_name("LPT1", "flac"), "LPT1_.flac");
+        assert_eq!(sanitizer.sanitize_dir_name("Console"), "Console");
+        assert_eq!(sanitizer.sanitize_dir_name("..."), "_");
+        assert_eq!(sanitizer.separator_replacement(), "_");
+    }
+
+    #[test]
+    fn test_sanitizer__strict_ascii() {
+        let sanitizer = sanitizer(SanitizePolicy::StrictAscii);
+
+        assert_eq!(
+            sanitizer.sanitize_dir_name("Sigur Rós: Ágætis byrjun"),
+            "Sigur Ros - Ag_tis byrjun"
+        );
+        assert_eq!(sanitizer.sanitize_dir_name("東京"), "__");
+    }
+
+    #[test]
+    fn test_sanitizer__truncate() {
+        let sanitizer = Sanitizer::new(SanitizePolicy::Windows, S("_"), vec![], 12);
+
+        assert_eq!(
+            sanitizer.sanitize_file_name("01. Long Title", "flac"),
+            "01. Lon.flac"
+        );
+        assert_eq!(
+            sanitizer.sanitize_file_name("01. Éé", "flac"),
+            "01. \u{c9}.flac"
+        );
+        assert_eq!(
+            sanitizer.sanitize_dir_name("abcdefghijklmn"),
+            "abcdefghijkl"
+        );
+
+        assert_eq!(sanitizer.add_suffix("A.flac", " (2)"), "A (2).flac");
+        assert_eq!(sanitizer.add_suffix("01. Lon.flac", " (2)"), "01. (2).flac");
+    }
+
+    #[test]
+    fn test_sanitizer__truncate_reserved() {
+        let sanitizer = Sanitizer::new(SanitizePolicy::Windows, S("_"), vec![], 3);
+
+        assert_eq!(sanitizer.sanitize_dir_name("CON"), "CO_");
+        assert_eq!(sanitizer.sanitize_dir_name("Console"), "Co_");
+        assert_eq!(sanitizer.sanitize_dir_name("NUL."), "NU_");
+        assert_eq!(sanitizer.sanitize_dir_name("Co"), "Co");
+    }
+}
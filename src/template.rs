@@ -0,0 +1,281 @@
+use std::convert::TryFrom;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use serde::Deserialize;
+
+use crate::metadata::MetaBlock;
+
+/// How the value of a field is formatted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// The value as it is.
+    Plain,
+    /// Numeric values are padded with zeros to this width (e.g. `{tracknumber:02}`).
+    Pad(usize),
+    /// The value is cut off after this many characters (e.g. `{date:4}`).
+    Truncate(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field {
+        key: String,
+        format: Format,
+    },
+    /// Left out entirely if any of its fields is missing.
+    Conditional(Vec<Segment>),
+}
+
+/// A template for output names, which references the keys of the merged tags
+/// of a track, such as `[{discnumber}-]{tracknumber:02} {artist} - {title}`.
+///
+/// - `{key}` is replaced by the value of `key`, and is an error if it is
+///   missing. Multiple values are joined with `, `.
+/// - `{key:0N}` pads a numeric value with zeros to a width of `N`, and
+///   `{key:N}` keeps only its first `N` characters.
+/// - `[...]` is a conditional segment, which is left out if any of the keys
+///   it references is missing.
+/// - `{{`, `}}`, `[[` and `]]` are literal braces and brackets.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+fn parse_field(chars: &mut Peekable<Chars>) -> Result<Segment, String> {
+    let mut field = String::new();
+
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => field.push(c),
+            None => return Err(String::from("unclosed '{'")),
+        }
+    }
+
+    let (key, spec) = match field.split_once(':') {
+        Some((key, spec)) => (key, Some(spec)),
+        None => (field.as_str(), None),
+    };
+
+    let key = key.trim().to_ascii_lowercase();
+    if key.is_empty() {
+        return Err(String::from("empty field name"));
+    }
+
+    let format = match spec {
+        None => Format::Plain,
+        Some(spec) => {
+            let width = spec
+                .parse::<usize>()
+                .ok()
+                .filter(|width| *width > 0)
+                .ok_or_else(|| format!("invalid format '{}' for field '{}'", spec, key))?;
+
+            if spec.starts_with('0') {
+                Format::Pad(width)
+            } else {
+                Format::Truncate(width)
+            }
+        }
+    };
+
+    Ok(Segment::Field { key, format })
+}
+
+fn flush(literal: &mut String, segments: &mut Vec<Segment>) {
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(std::mem::take(literal)));
+    }
+}
+
+fn parse_segments(
+    chars: &mut Peekable<Chars>,
+    is_conditional: bool,
+) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        // Doubled braces and brackets are always literals.
+        if matches!(c, '{' | '}' | '[' | ']') && chars.next_if_eq(&c).is_some() {
+            literal.push(c);
+            continue;
+        }
+
+        match c {
+            '{' => {
+                flush(&mut literal, &mut segments);
+                segments.push(parse_field(chars)?);
+            }
+            '[' if is_conditional => {
+                return Err(String::from("conditional segments cannot be nested"));
+            }
+            '[' => {
+                flush(&mut literal, &mut segments);
+                segments.push(Segment::Conditional(parse_segments(chars, true)?));
+            }
+            ']' if is_conditional => {
+                flush(&mut literal, &mut segments);
+                return Ok(segments);
+            }
+            '}' | ']' => return Err(format!("unmatched '{}'", c)),
+            c => literal.push(c),
+        }
+    }
+
+    if is_conditional {
+        return Err(String::from("unclosed '['"));
+    }
+
+    flush(&mut literal, &mut segments);
+
+    Ok(segments)
+}
+
+fn format_value(value: String, format: &Format) -> String {
+    match format {
+        Format::Plain => value,
+        Format::Pad(width) if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) => {
+            format!("{:0>width$}", value, width = width)
+        }
+        Format::Pad(_) => value,
+        Format::Truncate(width) => value.chars().take(*width).collect(),
+    }
+}
+
+/// Renders segments, or returns the first key that is missing from the tags.
+fn render_segments(
+    segments: &[Segment],
+    tags: &MetaBlock,
+    separator_replacement: &str,
+) -> Result<String, String> {
+    let mut rendered = String::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => rendered.push_str(literal),
+            Segment::Field { key, format } => {
+                let value = tags.get(key).ok_or_else(|| key.clone())?.to_string();
+
+                // Values must not introduce path separators.
+                let value = format_value(value, format).replace('/', separator_replacement);
+
+                rendered.push_str(&value);
+            }
+            Segment::Conditional(segments) => {
+                if let Ok(conditional) = render_segments(segments, tags, separator_replacement) {
+                    rendered.push_str(&conditional);
+                }
+            }
+        }
+    }
+
+    Ok(rendered)
+}
+
+impl Template {
+    /// Fills in the template with the given tags, replacing any `/` in their
+    /// values. On failure, returns the key that is missing outside of any
+    /// conditional segment.
+    pub fn render(&self, tags: &MetaBlock, separator_replacement: &str) -> Result<String, String> {
+        render_segments(&self.segments, tags, separator_replacement)
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = parse_segments(&mut s.chars().peekable(), false)?;
+
+        Ok(Self { segments })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    use big_s::S;
+    use maplit::btreemap;
+
+    use crate::metadata::MetaVal;
+
+    fn render(template: &str, tags: &MetaBlock) -> Result<String, String> {
+        template.parse::<Template>().unwrap().render(tags, "")
+    }
+
+    #[test]
+    fn test_template__render() {
+        let tags = btreemap! {
+            S("artist") => MetaVal::Many(vec![S("A"), S("B")]),
+            S("date") => MetaVal::One(S("1999-03-01")),
+            S("title") => MetaVal::One(S("Either/Or")),
+            S("tracknumber") => MetaVal::One(S("3")),
+        };
+
+        assert_eq!(
+            render("{tracknumber:02} {artist} - {title}", &tags),
+            Ok(S("03 A, B - EitherOr"))
+        );
+        assert_eq!(
+            render("{DATE:4} {{{title}}} [[{tracknumber:3}]]", &tags),
+            Ok(S("1999 {EitherOr} [3]"))
+        );
+        assert_eq!(render("{title:02}", &tags), Ok(S("EitherOr")));
+
+        let template = "{title}".parse::<Template>().unwrap();
+        assert_eq!(template.render(&tags, "-"), Ok(S("Either-Or")));
+    }
+
+    #[test]
+    fn test_template__conditional() {
+        let tags = btreemap! {
+            S("discnumber") => MetaVal::One(S("2")),
+            S("tracknumber") => MetaVal::One(S("5")),
+        };
+        let template = "[{discnumber}-]{tracknumber:02}[ ({version})]";
+
+        assert_eq!(render(template, &tags), Ok(S("2-05")));
+        assert_eq!(
+            render(
+                template,
+                &btreemap! { S("tracknumber") => MetaVal::One(S("5")) }
+            ),
+            Ok(S("05"))
+        );
+        assert_eq!(render("{version}", &tags), Err(S("version")));
+    }
+
+    #[test]
+    fn test_template__parse_errors() {
+        for template in [
+            "{title",
+            "title}",
+            "[{discnumber}",
+            "{discnumber}]",
+            "[[{discnumber}-]",
+            "[[{a}]-]",
+            "[a[{b}]]",
+            "{}",
+            "{title:}",
+            "{title:x}",
+            "{title:0}",
+        ] {
+            assert!(template.parse::<Template>().is_err(), "{}", template);
+        }
+    }
+}
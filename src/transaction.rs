@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use crate::error::{Error, Result};
+
+/// Creates a hidden temporary directory inside of the given directory, so
+/// that files can be renamed between the two without crossing filesystems.
+fn hidden_temp_dir_in(dir: &Path) -> Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix(".marktag-")
+        .tempdir_in(dir)
+        .map_err(Error::io(dir))
+}
+
+/// Creates a directory along with any missing parents. Returns the created
+/// directories, outermost first.
+pub(crate) fn create_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut missing = dir
+        .ancestors()
+        .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    missing.reverse();
+
+    std::fs::create_dir_all(dir).map_err(Error::io(dir))?;
+
+    Ok(missing)
+}
+
+/// Removes the directories created by [`create_dirs`], innermost first, for
+/// as long as they are empty.
+pub(crate) fn remove_created_dirs(dirs: Vec<PathBuf>) {
+    for dir in dirs.into_iter().rev() {
+        if std::fs::remove_dir(&dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// A step of a commit, recorded so that it can be undone.
+enum Step {
+    /// A file was moved aside from `original` to `backup`.
+    MovedAside { original: PathBuf, backup: PathBuf },
+    /// A staged file was moved into place at this path.
+    Placed(PathBuf),
+}
+
+/// A set of file changes that either all take effect, or none do.
+///
+/// Output files are first staged in a hidden directory inside of the output
+/// directory, where they can be freely modified. Nothing outside of the
+/// staging directory is touched until [`Transaction::commit`], which moves
+/// the staged files into place and removes the files scheduled for removal.
+/// If any step of the commit fails, every earlier step is undone. Dropping a
+/// transaction without committing it discards the staged files.
+pub(crate) struct Transaction {
+    staging_dir: TempDir,
+    outputs: Vec<(PathBuf, PathBuf)>,
+    removals: Vec<PathBuf>,
+}
+
+impl Transaction {
+    pub fn new(output_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            staging_dir: hidden_temp_dir_in(output_dir)?,
+            outputs: Vec::new(),
+            removals: Vec::new(),
+        })
+    }
+
+    pub fn staging_dir(&self) -> &Path {
+        self.staging_dir.path()
+    }
+
+    /// Reserves a path in the staging directory for a file that will be moved
+    /// to `dest` on commit. The caller is responsible for creating the file.
+    pub fn stage(&mut self, dest: PathBuf) -> PathBuf {
+        // Prefix with a counter, so that staged files never overwrite each
+        // other even if their destinations share a file name.
+        let file_name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let staged_file_name = format!("{}-{}", self.outputs.len(), file_name);
+        let staged_path = self.staging_dir.path().join(staged_file_name);
+
+        self.outputs.push((staged_path.clone(), dest));
+
+        staged_path
+    }
+
+    /// Stages a copy of an existing file, which will be moved to `dest` on
+    /// commit. Returns the path of the copy.
+    pub fn stage_copy(&mut self, source: &Path, dest: PathBuf) -> Result<PathBuf> {
+        let staged_path = self.stage(dest);
+        std::fs::copy(source, &staged_path).map_err(Error::io(source))?;
+
+        Ok(staged_path)
+    }
+
+    /// Schedules an existing file to be removed on commit.
+    pub fn remove(&mut self, path: PathBuf) {
+        self.removals.push(path);
+    }
+
+    /// Moves a file aside into a backup directory next to it, so that it can
+    /// be restored if the commit fails.
+    fn move_aside(
+        backup_dirs: &mut BTreeMap<PathBuf, TempDir>,
+        steps: &mut Vec<Step>,
+        original: &Path,
+    ) -> Result<()> {
+        let parent = original.parent().unwrap_or_else(|| Path::new("."));
+
+        if !backup_dirs.contains_key(parent) {
+            backup_dirs.insert(parent.to_path_buf(), hidden_temp_dir_in(parent)?);
+        }
+
+        let backup = backup_dirs[parent].path().join(format!("{}", steps.len()));
+
+        std::fs::rename(original, &backup).map_err(Error::io(original))?;
+        steps.push(Step::MovedAside {
+            original: original.to_path_buf(),
+            backup,
+        });
+
+        Ok(())
+    }
+
+    /// Runs the steps of a commit, recording each one as it succeeds.
+    fn apply(
+        &self,
+        backup_dirs: &mut BTreeMap<PathBuf, TempDir>,
+        steps: &mut Vec<Step>,
+    ) -> Result<()> {
+        for path in self.removals.iter() {
+            Self::move_aside(backup_dirs, steps, path)?;
+        }
+
+        for (staged_path, dest) in self.outputs.iter() {
+            // Existing files that are overwritten must be restored as well.
+            if dest.exists() {
+                Self::move_aside(backup_dirs, steps, dest)?;
+            }
+
+            std::fs::rename(staged_path, dest).map_err(Error::io(dest))?;
+            steps.push(Step::Placed(dest.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Undoes the given steps in reverse order. Returns whether every step
+    /// could be undone.
+    fn undo(steps: Vec<Step>) -> bool {
+        let mut is_restored = true;
+
+        for step in steps.into_iter().rev() {
+            let result = match &step {
+                Step::Placed(dest) => std::fs::remove_file(dest),
+                Step::MovedAside { original, backup } => std::fs::rename(backup, original),
+            };
+
+            if let Err(err) = result {
+                is_restored = false;
+
+                match step {
+                    Step::Placed(dest) => {
+                        eprintln!("Unable to remove {}: {}", dest.display(), err)
+                    }
+                    Step::MovedAside { original, backup } => eprintln!(
+                        "Unable to restore {} from {}: {}",
+                        original.display(),
+                        backup.display(),
+                        err
+                    ),
+                }
+            }
+        }
+
+        is_restored
+    }
+
+    /// Moves the staged files into place, and removes the files scheduled for
+    /// removal. On failure, the affected directories are restored to their
+    /// prior state before the error is returned.
+    pub fn commit(self) -> Result<()> {
+        let mut backup_dirs = BTreeMap::new();
+        let mut steps = Vec::new();
+
+        let result = self.apply(&mut backup_dirs, &mut steps);
+
+        if result.is_err() {
+            eprintln!("Rolling back changes");
+
+            if !Self::undo(steps) {
+                // Keep the backups around, since they are the only copies of
+                // the files that could not be restored.
+                for (_, backup_dir) in backup_dirs {
+                    eprintln!("Keeping backup dir: {}", backup_dir.keep().display());
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    /// Lists the files in a directory with their contents, ignoring hidden
+    /// entries such as staging directories.
+    fn list_files(dir: &Path) -> BTreeMap<String, String> {
+        dir.read_dir()
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| !p.file_name().unwrap().to_string_lossy().starts_with('.'))
+            .map(|p| {
+                let name = p.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read_to_string(&p).unwrap())
+            })
+            .collect()
+    }
+
+    fn count_entries(dir: &Path) -> usize {
+        dir.read_dir().unwrap().count()
+    }
+
+    #[test]
+    fn test_transaction__commit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        std::fs::write(dir.join("a.flac"), "a").unwrap();
+        std::fs::write(dir.join("b.flac"), "b").unwrap();
+
+        let mut transaction = Transaction::new(dir).unwrap();
+        let staged_a = transaction
+            .stage_copy(&dir.join("a.flac"), dir.join("1. A.flac"))
+            .unwrap();
+        std::fs::write(&staged_a, "tagged a").unwrap();
+        transaction.remove(dir.join("a.flac"));
+
+        // Overwriting a file that is also being removed.
+        transaction
+            .stage_copy(&dir.join("b.flac"), dir.join("b.flac"))
+            .unwrap();
+        transaction.remove(dir.join("b.flac"));
+
+        // Nothing changes before the commit.
+        assert_eq!(list_files(dir).len(), 2);
+
+        transaction.commit().unwrap();
+
+        let expected = [("1. A.flac", "tagged a"), ("b.flac", "b")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(list_files(dir), expected);
+        assert_eq!(count_entries(dir), 2);
+    }
+
+    #[test]
+    fn test_transaction__drop_without_commit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        std::fs::write(dir.join("a.flac"), "a").unwrap();
+        let before = list_files(dir);
+
+        {
+            let mut transaction = Transaction::new(dir).unwrap();
+            transaction
+                .stage_copy(&dir.join("a.flac"), dir.join("1. A.flac"))
+                .unwrap();
+            transaction.remove(dir.join("a.flac"));
+        }
+
+        assert_eq!(list_files(dir), before);
+        assert_eq!(count_entries(dir), 1);
+    }
+
+    #[test]
+    fn test_create_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let artist_dir = temp_dir.path().join("Artist");
+        let album_dir = artist_dir.join("1999 - Album");
+
+        let created = create_dirs(&album_dir).unwrap();
+        assert_eq!(created, vec![artist_dir.clone(), album_dir.clone()]);
+        assert!(album_dir.is_dir());
+        assert_eq!(create_dirs(&album_dir).unwrap(), Vec::<PathBuf>::new());
+
+        // Directories that are no longer empty are left alone.
+        std::fs::write(artist_dir.join("artist.jpg"), "").unwrap();
+        remove_created_dirs(created);
+        assert!(!album_dir.exists());
+        assert!(artist_dir.exists());
+    }
+
+    #[test]
+    fn test_transaction__rollback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let output_dir = temp_dir.path().join("output");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&output_dir).unwrap();
+
+        std::fs::write(source_dir.join("a.flac"), "a").unwrap();
+        std::fs::write(source_dir.join("b.flac"), "b").unwrap();
+        std::fs::write(output_dir.join("1. A.flac"), "old").unwrap();
+        let source_before = list_files(&source_dir);
+        let output_before = list_files(&output_dir);
+
+        let mut transaction = Transaction::new(&output_dir).unwrap();
+        for (name, dest) in [("a.flac", "1. A.flac"), ("b.flac", "2. B.flac")] {
+            transaction
+                .stage_copy(&source_dir.join(name), output_dir.join(dest))
+                .unwrap();
+            transaction.remove(source_dir.join(name));
+        }
+
+        // Make the last step of the commit fail.
+        std::fs::remove_file(transaction.outputs[1].0.clone()).unwrap();
+
+        assert!(transaction.commit().is_err());
+
+        assert_eq!(list_files(&source_dir), source_before);
+        assert_eq!(list_files(&output_dir), output_before);
+        assert_eq!(count_entries(&source_dir), 2);
+        assert_eq!(count_entries(&output_dir), 1);
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt::Display;
+
+use metaflac::Tag;
+
+use crate::cache::LoudnessCache;
+use crate::error::Result;
+use crate::helpers::{self, Track};
+use crate::loudness::{self, Measurement, ReferenceLevel, ScannedTrack};
+
+/// Parses the leading number of a ReplayGain tag value, ignoring any unit
+/// that follows it (e.g. `-7.23 dB` or `-18.00 LUFS`).
+fn parse_tag_number(value: &str) -> Option<f32> {
+    value
+        .split_whitespace()
+        .next()
+        .and_then(|n| n.parse::<f32>().ok())
+        .filter(|n| n.is_finite())
+}
+
+fn get_tag_number(tag: &Tag, key: &str) -> Option<f32> {
+    tag.get_vorbis(key)
+        .and_then(|mut vals| vals.next())
+        .and_then(parse_tag_number)
+}
+
+/// The ReplayGain values stored in a track's existing tags.
+struct StoredGain {
+    track_gain: Option<f32>,
+    track_peak: Option<f32>,
+    album_gain: Option<f32>,
+    album_peak: Option<f32>,
+    reference: ReferenceLevel,
+}
+
+impl StoredGain {
+    fn from_tag(tag: &Tag) -> Self {
+        // Only a reference loudness given in LUFS is meaningful here. Older
+        // tools write the ReplayGain 1 reference of 89 dB SPL, which is
+        // considered equivalent to the ReplayGain 2 reference.
+        let reference = tag
+            .get_vorbis("replaygain_reference_loudness")
+            .and_then(|mut vals| vals.next())
+            .filter(|v| v.ends_with("LUFS") || v.ends_with("LKFS"))
+            .and_then(parse_tag_number)
+            .map(ReferenceLevel::Custom)
+            .unwrap_or(ReferenceLevel::ReplayGain2);
+
+        Self {
+            track_gain: get_tag_number(tag, "replaygain_track_gain"),
+            track_peak: get_tag_number(tag, "replaygain_track_peak"),
+            album_gain: get_tag_number(tag, "replaygain_album_gain"),
+            album_peak: get_tag_number(tag, "replaygain_album_peak"),
+            reference,
+        }
+    }
+}
+
+/// Whether a mismatch concerns a track's own values or its album values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    Track,
+    Album,
+}
+
+/// A stored ReplayGain value that is missing, or that deviates from a fresh
+/// measurement by more than the allowed tolerance.
+#[derive(Debug)]
+pub(crate) struct Mismatch {
+    pub scope: Scope,
+    pub disc: Option<usize>,
+    pub track: usize,
+    pub file: String,
+    pub field: &'static str,
+    pub stored: Option<f32>,
+    pub expected: f32,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = match self.scope {
+            Scope::Track => "track",
+            Scope::Album => "album",
+        };
+
+        write!(
+            f,
+            "Track {} ({}): {} {} ",
+            helpers::format_position(self.disc, self.track),
+            self.file,
+            scope,
+            self.field
+        )?;
+
+        match self.stored {
+            Some(stored) => write!(f, "is {:.6}", stored)?,
+            None => write!(f, "is missing")?,
+        }
+
+        write!(f, ", expected {:.6}", self.expected)
+    }
+}
+
+/// Allowed deviations between stored and measured values, in dB.
+pub(crate) struct Tolerances {
+    pub gain: f32,
+    pub peak: f32,
+}
+
+struct Checker<'a> {
+    scanned_track: &'a ScannedTrack,
+    tolerances: &'a Tolerances,
+    mismatches: Vec<Mismatch>,
+}
+
+impl<'a> Checker<'a> {
+    fn push(&mut self, scope: Scope, field: &'static str, stored: Option<f32>, expected: f32) {
+        let track = &self.scanned_track.track;
+
+        self.mismatches.push(Mismatch {
+            scope,
+            disc: track.disc,
+            track: track.index,
+            file: track
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            field,
+            stored,
+            expected,
+        });
+    }
+
+    fn check_gain(
+        &mut self,
+        scope: Scope,
+        stored: Option<f32>,
+        measurement: &Measurement,
+        reference: ReferenceLevel,
+    ) {
+        let expected = measurement.loudness.gain(reference);
+
+        // Gains cannot be calculated for silent audio, so there is nothing
+        // to compare against.
+        if !expected.is_finite() {
+            return;
+        }
+
+        match stored {
+            Some(stored) if (stored - expected).abs() <= self.tolerances.gain => {}
+            _ => self.push(scope, "gain", stored, expected),
+        }
+    }
+
+    fn check_peak(&mut self, scope: Scope, stored: Option<f32>, measurement: &Measurement) {
+        // Older tools store the sample peak rather than the true peak, so
+        // either is accepted.
+        let tolerance = self.tolerances.peak;
+        let within_tolerance = |stored: f32, expected: f32| {
+            stored == expected || (20.0 * (stored / expected).log10()).abs() <= tolerance
+        };
+
+        match stored {
+            Some(stored)
+                if within_tolerance(stored, measurement.true_peak)
+                    || within_tolerance(stored, measurement.sample_peak) => {}
+            _ => self.push(scope, "peak", stored, measurement.true_peak),
+        }
+    }
+}
+
+/// Album values are stored in the tags of every track, so an album value that
+/// is wrong in the same way on several tracks is only reported once, for the
+/// first of them.
+fn dedup_album_mismatches(mismatches: Vec<Mismatch>) -> Vec<Mismatch> {
+    let mut deduped: Vec<Mismatch> = Vec::with_capacity(mismatches.len());
+
+    for mismatch in mismatches {
+        let is_duplicate = mismatch.scope == Scope::Album
+            && deduped.iter().any(|other| {
+                other.scope == Scope::Album
+                    && other.field == mismatch.field
+                    && other.stored == mismatch.stored
+            });
+
+        if !is_duplicate {
+            deduped.push(mismatch);
+        }
+    }
+
+    deduped
+}
+
+/// Measures the loudness of the given tracks, and compares the results with
+/// the ReplayGain values stored in their existing tags.
+pub(crate) fn verify_tracks(
+    tracks: Vec<Track>,
+    tolerances: &Tolerances,
+    jobs: usize,
+    cache: Option<&mut LoudnessCache>,
+) -> Result<Vec<Mismatch>> {
+    let analysis_output = loudness::analyze_tracks(tracks, jobs, cache)?;
+    let album = &analysis_output.album;
+
+    let mut mismatches = Vec::new();
+
+    for scanned_track in analysis_output.scanned_tracks.iter() {
+        let stored = StoredGain::from_tag(&scanned_track.track.tag);
+
+        let mut checker = Checker {
+            scanned_track,
+            tolerances,
+            mismatches: Vec::new(),
+        };
+
+        let track = &scanned_track.measurement;
+
+        checker.check_gain(Scope::Track, stored.track_gain, track, stored.reference);
+        checker.check_peak(Scope::Track, stored.track_peak, track);
+        checker.check_gain(Scope::Album, stored.album_gain, album, stored.reference);
+        checker.check_peak(Scope::Album, stored.album_peak, album);
+
+        mismatches.extend(checker.mismatches);
+    }
+
+    Ok(dedup_album_mismatches(mismatches))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_parse_tag_number() {
+        assert_eq!(parse_tag_number("-7.23 dB"), Some(-7.23));
+        assert_eq!(parse_tag_number("+1.50 dB"), Some(1.5));
+        assert_eq!(parse_tag_number("-18.00 LUFS"), Some(-18.0));
+        assert_eq!(parse_tag_number("0.988525"), Some(0.988525));
+        assert_eq!(parse_tag_number("  0.5  "), Some(0.5));
+
+        assert_eq!(parse_tag_number(""), None);
+        assert_eq!(parse_tag_number("dB"), None);
+        assert_eq!(parse_tag_number("inf dB"), None);
+    }
+
+    fn mismatch(scope: Scope, track: usize, field: &'static str, stored: f32) -> Mismatch {
+        Mismatch {
+            scope,
+            disc: None,
+            track,
+            file: format!("{:02}.flac", track),
+            field,
+            stored: Some(stored),
+            expected: -6.0,
+        }
+    }
+
+    #[test]
+    fn test_dedup_album_mismatches() {
+        let mismatches = vec![
+            mismatch(Scope::Track, 1, "gain", -7.0),
+            mismatch(Scope::Album, 1, "gain", -7.0),
+            mismatch(Scope::Track, 2, "gain", -7.0),
+            mismatch(Scope::Album, 2, "gain", -7.0),
+            mismatch(Scope::Album, 2, "peak", -7.0),
+            mismatch(Scope::Album, 3, "gain", -8.0),
+        ];
+
+        let deduped = dedup_album_mismatches(mismatches)
+            .iter()
+            .map(|m| (m.scope, m.track, m.field))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deduped,
+            vec![
+                (Scope::Track, 1, "gain"),
+                (Scope::Album, 1, "gain"),
+                (Scope::Track, 2, "gain"),
+                (Scope::Album, 2, "peak"),
+                (Scope::Album, 3, "gain"),
+            ]
+        );
+    }
+}
@@ -2,51 +2,233 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-use metaflac::{BlockType, Tag};
+use clap::ValueEnum;
+use metaflac::block::{Picture, PictureType};
+use metaflac::{Block, BlockType, Tag};
 
 use crate::{
+    error::{Error, Result},
     helpers::Track,
+    loudness::{Loudness, Measurement, ReferenceLevel, ScannedTrack},
     metadata::{MetaBlock, Metadata},
 };
 
-/// Helper method to write the combined metadata file into the final output
-/// directory, alongside the newly-tagged tracks.
-pub(crate) fn write_output_metadata_file(output_dir: &Path, metadata: &Metadata) {
-    let metadata_fp = output_dir.join("meta.json");
-    let serialized = serde_json::to_string_pretty(metadata).unwrap();
-    let mut file = File::create(metadata_fp).unwrap();
-    writeln!(&mut file, "{}", &serialized).unwrap();
+/// Helper method to write the combined metadata file, which ends up in the
+/// final output directory alongside the newly-tagged tracks.
+pub(crate) fn write_output_metadata_file(metadata_fp: &Path, metadata: &Metadata) -> Result<()> {
+    let serialized =
+        serde_json::to_string_pretty(metadata).expect("metadata is always serializable");
+    let mut file = File::create(metadata_fp).map_err(Error::io(metadata_fp))?;
+    writeln!(&mut file, "{}", &serialized).map_err(Error::io(metadata_fp))
 }
 
+/// Which pictures end up embedded in the retagged tracks.
+pub(crate) struct PictureOptions {
+    /// Keep the pictures that are already embedded. A new cover replaces
+    /// only the existing front covers.
+    pub keep_existing: bool,
+    /// A front cover to embed in every track.
+    pub cover: Option<Picture>,
+}
+
+/// Replaces the tags of a track with the given tags, and updates its pictures.
 pub(crate) fn write_tags_to_track(
     track: &Track,
-    total_num_tracks: usize,
-    incoming_album_block: MetaBlock,
-    incoming_track_block: MetaBlock,
-) {
-    println!("Writing new tags to file: {}", track.path.display());
-    let mut flac_tag = Tag::read_from_path(&track.path).unwrap();
+    tags: MetaBlock,
+    picture_options: &PictureOptions,
+) -> Result<()> {
+    eprintln!("Writing new tags to file: {}", track.path.display());
+    let mut flac_tag = Tag::read_from_path(&track.path).map_err(Error::tag(&track.path))?;
 
-    // Remove all tags and pictures.
+    // Remove all tags, and any pictures that are not kept.
     flac_tag.remove_blocks(BlockType::VorbisComment);
-    flac_tag.remove_blocks(BlockType::Picture);
 
-    // Add in album block fields.
-    for (k, v) in incoming_album_block {
-        flac_tag.set_vorbis(k, v.into_vec());
+    if !picture_options.keep_existing {
+        flac_tag.remove_blocks(BlockType::Picture);
     }
 
-    // Add in track block fields.
-    for (k, v) in incoming_track_block {
+    if let Some(cover) = &picture_options.cover {
+        flac_tag.remove_picture_type(PictureType::CoverFront);
+        flac_tag.push_block(Block::Picture(cover.clone()));
+    }
+
+    // Add in the merged album and track fields.
+    for (k, v) in tags {
         flac_tag.set_vorbis(k, v.into_vec());
     }
 
-    // Add track index/count fields.
-    flac_tag.set_vorbis(String::from("tracknumber"), vec![track.index.to_string()]);
+    flac_tag.save().map_err(Error::tag(&track.path))
+}
+
+/// Which family of loudness normalization tags to write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum GainTagFormat {
+    /// ReplayGain tags (gain, peak and reference loudness).
+    #[value(name = "replaygain")]
+    ReplayGain,
+    /// Opus-style R128 gain tags, as Q7.8 fixed point integers.
+    R128,
+}
+
+impl GainTagFormat {
+    /// The reference level conventionally used with this tag format.
+    pub fn default_reference(&self) -> ReferenceLevel {
+        match self {
+            Self::ReplayGain => ReferenceLevel::ReplayGain2,
+            Self::R128 => ReferenceLevel::EbuR128,
+        }
+    }
+}
+
+pub(crate) struct GainOptions {
+    pub format: GainTagFormat,
+    pub reference: ReferenceLevel,
+    /// Also write loudness range tags (ReplayGain format only).
+    pub write_range: bool,
+}
+
+impl GainOptions {
+    /// The tags written from the loudness analysis with these options, after
+    /// the new tags are in place.
+    pub fn tag_keys(&self) -> Vec<&'static str> {
+        match self.format {
+            GainTagFormat::ReplayGain => {
+                let mut keys = vec![
+                    "replaygain_album_gain",
+                    "replaygain_album_peak",
+                    "replaygain_algorithm",
+                    "replaygain_reference_loudness",
+                    "replaygain_track_gain",
+                    "replaygain_track_peak",
+                ];
+                if self.write_range {
+                    keys.extend(["replaygain_album_range", "replaygain_track_range"]);
+                }
+                keys
+            }
+            GainTagFormat::R128 => vec!["r128_album_gain", "r128_track_gain"],
+        }
+    }
+}
+
+/// Calculates the gain needed for a loudness to reach the reference level, or
+/// `None` if the loudness could not be measured (e.g. a fully silent track).
+fn finite_gain(loudness: &Loudness, reference: ReferenceLevel) -> Option<f32> {
+    Some(loudness.gain(reference)).filter(|gain| gain.is_finite())
+}
+
+/// Converts a gain in dB to the Q7.8 fixed point format used by R128 tags.
+fn to_q7_8(gain: f32) -> i16 {
+    (gain * 256.0)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+fn set_replaygain_tags(
+    flac_tag: &mut Tag,
+    track: &Measurement,
+    album: &Measurement,
+    options: &GainOptions,
+) {
+    flac_tag.set_vorbis(
+        String::from("replaygain_algorithm"),
+        vec![String::from("ITU-R BS.1770")],
+    );
     flac_tag.set_vorbis(
-        String::from("totaltracks"),
-        vec![total_num_tracks.to_string()],
+        String::from("replaygain_reference_loudness"),
+        vec![format!("{:.2} LUFS", options.reference.lkfs())],
     );
 
-    flac_tag.save().unwrap();
+    if let Some(track_gain) = finite_gain(&track.loudness, options.reference) {
+        flac_tag.set_vorbis(
+            String::from("replaygain_track_gain"),
+            vec![format!("{:.2} dB", track_gain)],
+        );
+    }
+    flac_tag.set_vorbis(
+        String::from("replaygain_track_peak"),
+        vec![format!("{:.6}", track.peak())],
+    );
+
+    if let Some(album_gain) = finite_gain(&album.loudness, options.reference) {
+        flac_tag.set_vorbis(
+            String::from("replaygain_album_gain"),
+            vec![format!("{:.2} dB", album_gain)],
+        );
+    }
+    flac_tag.set_vorbis(
+        String::from("replaygain_album_peak"),
+        vec![format!("{:.6}", album.peak())],
+    );
+
+    if options.write_range {
+        flac_tag.set_vorbis(
+            String::from("replaygain_track_range"),
+            vec![format!("{:.2} dB", track.range)],
+        );
+        flac_tag.set_vorbis(
+            String::from("replaygain_album_range"),
+            vec![format!("{:.2} dB", album.range)],
+        );
+    }
+}
+
+fn set_r128_tags(
+    flac_tag: &mut Tag,
+    track: &Measurement,
+    album: &Measurement,
+    options: &GainOptions,
+) {
+    if let Some(track_gain) = finite_gain(&track.loudness, options.reference) {
+        flac_tag.set_vorbis(
+            String::from("r128_track_gain"),
+            vec![to_q7_8(track_gain).to_string()],
+        );
+    }
+
+    if let Some(album_gain) = finite_gain(&album.loudness, options.reference) {
+        flac_tag.set_vorbis(
+            String::from("r128_album_gain"),
+            vec![to_q7_8(album_gain).to_string()],
+        );
+    }
+}
+
+pub(crate) fn write_gain_tags(
+    scanned_track: &ScannedTrack,
+    album: &Measurement,
+    options: &GainOptions,
+) -> Result<()> {
+    let path = &scanned_track.track.path;
+
+    eprintln!("Writing gain tags to file: {}", path.display());
+    let mut flac_tag = Tag::read_from_path(path).map_err(Error::tag(path))?;
+
+    let track = &scanned_track.measurement;
+
+    match options.format {
+        GainTagFormat::ReplayGain => set_replaygain_tags(&mut flac_tag, track, album, options),
+        GainTagFormat::R128 => set_r128_tags(&mut flac_tag, track, album, options),
+    }
+
+    flac_tag.save().map_err(Error::tag(path))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_to_q7_8() {
+        assert_eq!(to_q7_8(0.0), 0);
+        assert_eq!(to_q7_8(1.0), 256);
+        assert_eq!(to_q7_8(-5.5), -1408);
+        assert_eq!(to_q7_8(0.01), 3);
+
+        // Out of range gains are clamped.
+        assert_eq!(to_q7_8(200.0), i16::MAX);
+        assert_eq!(to_q7_8(-200.0), i16::MIN);
+    }
 }